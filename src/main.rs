@@ -1,21 +1,29 @@
 use calamine::{open_workbook_auto, DataType, Reader};
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, NaiveDateTime};
 use regex::Regex;
 use serde::Deserialize;
 use serde_json::{Map as JsonMap, Value};
 use std::cmp::Ordering;
-use std::collections::{BTreeSet, HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::env;
 use std::fs;
 use std::io::{self, Read};
 use std::path::Path;
+use std::sync::OnceLock;
 use umya_spreadsheet as umya;
+use unicode_normalization::UnicodeNormalization;
 
 const VERSION: &str = "0.7.0";
 
+// One sheet row / input record: column key -> value
+type RowMap = HashMap<String, Value>;
+
 #[derive(Debug, Default, Deserialize)]
 struct ConfigFile {
     out: Option<String>,
     sheet: Option<String>,
+    input: Option<String>, // JSON file to read instead of stdin
+    sheet_by: Option<String>, // one sheet per value of this column; `sheet` is the template
     ndjson: Option<bool>,
     pk: Option<Vec<String>>,
 
@@ -24,18 +32,113 @@ struct ConfigFile {
     include_regex: Option<Vec<String>>,
     include_substr: Option<Vec<String>>,
 
+    // exclude filters (applied after inclusion; PKs are never excluded)
+    exclude: Option<Vec<String>>,
+    exclude_regex: Option<Vec<String>>,
+    exclude_substr: Option<Vec<String>>,
+
     // ordering
     order: Option<Vec<String>>,
     order_regex: Option<Vec<String>>,
     order_substr: Option<Vec<String>>,
     order_rest: Option<String>, // existing|alpha|none
 
+    // stale cells outside the written range: none|values|remove (default none)
+    clear: Option<String>,
+
+    // PK matching: column (or "*") -> ["trim", "casefold", "numeric", "nfc"]
+    #[serde(default)]
+    pk_normalize: HashMap<String, Vec<String>>,
+
+    // rows sharing a PK: error|first-wins|last-wins|keep-all (default last-wins)
+    duplicates: Option<String>,
+
+    // how a matched row takes the input: replace|patch|patch-non-null (default replace)
+    merge: Option<String>,
+    // columns whose non-empty sheet values the input never overwrites
+    protect: Option<Vec<String>>,
+
     // whether PKs are forced to appear first (default true)
     pk_first: Option<bool>,
 
-    // NEW: per-column hyperlink bases (exact column names)
+    // flatten nested objects/arrays into dotted keys (default false)
+    flatten: Option<bool>,
+    flatten_sep: Option<String>,   // default "."
+    flatten_depth: Option<usize>,  // max key segments; deeper values stay JSON
+
+    // JSON Pointer (/issues) or dotted path (issues) to the record list
+    root: Option<String>,
+
+    // one row per array element (dotted path or JSON Pointer); applied before flatten
+    explode: Option<Vec<String>>,
+    // per exploded path: child field that identifies an element (default: element index)
+    #[serde(default)]
+    explode_key: HashMap<String, String>,
+
+    // collapse indexed columns: pattern -> column (or { column, sep })
+    #[serde(default)]
+    collapse: HashMap<String, CollapseRule>,
+    collapse_sep: Option<String>, // default delimiter (", ")
+
+    // NEW: per-column hyperlinks (exact column names): URL base/template or
+    // { url = "...", text = "...", encode = true }
+    #[serde(default)]
+    hyperlink: HashMap<String, HyperlinkRule>,
+    link_mode: Option<String>, // formula|native (default formula)
+
+    // display headers: key -> label (mapped back to keys on readback)
+    #[serde(default)]
+    labels: HashMap<String, String>,
+
+    // per-column type hints: datetime|date|number|text
+    #[serde(default)]
+    types: HashMap<String, String>,
+    auto_dates: Option<bool>,        // detect ISO-8601 strings in untyped columns
+    timezone: Option<String>,        // utc|local|drop (default drop)
+    date_format: Option<String>,     // default "yyyy-mm-dd"
+    datetime_format: Option<String>, // default "yyyy-mm-dd hh:mm:ss"
+
+    // per-column cell formats; applied to new cells (all cells with format_existing)
     #[serde(default)]
-    hyperlink: HashMap<String, String>,
+    format: HashMap<String, ColumnFormat>,
+    format_existing: Option<bool>,
+
+    // style (and height) for appended rows: copy the last data row, or `template_row`
+    inherit_row_style: Option<bool>,
+    template_row: Option<u32>, // 1-based sheet row; implies inherit_row_style
+
+    // Excel Table over header + data: "Name" or { name, style, ... }
+    table: Option<TableRule>,
+
+    // sheet view
+    freeze: Option<String>,     // header|pk|cell like "B2"
+    autofilter: Option<bool>,   // filter buttons on the header row (default false)
+    auto_width: Option<bool>,   // fit column widths to content (default false)
+    min_width: Option<f64>,     // default 8
+    max_width: Option<f64>,     // default 60
+
+    // audit columns _first_seen/_last_updated/_last_seen, maintained by the merge
+    audit: Option<bool>,
+    audit_position: Option<String>, // first (after PKs) | end (default)
+
+    // change tracking: highlight changed cells / new rows, log changes to a sheet
+    track_changes: Option<bool>,
+    changed_fill: Option<String>,  // default "FFEB9C"
+    new_fill: Option<String>,      // default "C6EFCE"
+    changes_sheet: Option<String>, // default "_changes"
+
+    // per-run snapshot of the result: none|sheet|history
+    snapshot: Option<String>,
+    snapshot_name: Option<String>, // sheet mode, default "{sheet} {date}"
+    history_sheet: Option<String>, // history mode, default "History"
+    snapshot_keep: Option<usize>,  // keep the last N snapshots (default: all)
+
+    // rows whose PK is missing from the input: none|remove|archive|mark (needs pk)
+    sync: Option<String>,
+    archive_sheet: Option<String>,  // default "Archive"
+    status_column: Option<String>,  // default "_status"
+    status_removed: Option<String>, // default "removed"
+    strike_removed: Option<bool>,   // strike-through marked rows (default false)
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -50,289 +153,1288 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         return Ok(());
     }
 
-    // -------- config (optional)
+    // -------- config (optional); one entry per [[job]], or the whole file
     let cfg_path = get_arg_value(&args, "--config").or_else(|| get_arg_value(&args, "-c"));
-    let cfg = if let Some(path) = cfg_path.as_deref() {
-        Some(load_config(path)?)
+    let jobs: Vec<Option<ConfigFile>> = if let Some(path) = cfg_path.as_deref() {
+        load_jobs(path)?.into_iter().map(Some).collect()
     } else {
-        None
+        vec![None]
     };
 
-    // -------- resolve options
-    let out_path = get_arg_value(&args, "--out")
-        .or_else(|| get_arg_value(&args, "-o"))
-        .or_else(|| cfg.as_ref().and_then(|c| c.out.clone()))
-        .expect("--out <FILE.xlsx> is required (or set in config)");
+    // -------- one workbook for all jobs
+    let mut outs: Vec<String> = jobs.iter().filter_map(|c| c.as_ref()?.out.clone()).collect();
+    outs.dedup();
+    let out_path = match get_arg_value(&args, "--out").or_else(|| get_arg_value(&args, "-o")) {
+        Some(out) => out,
+        None if outs.len() > 1 => {
+            return Err(format!("All jobs write one workbook, but `out` differs: {}", outs.join(", ")).into());
+        }
+        None => outs
+            .pop()
+            .expect("--out <FILE.xlsx> is required (or set in config)"),
+    };
     if !out_path.to_lowercase().ends_with(".xlsx") {
         eprintln!("--out must end with .xlsx");
         std::process::exit(2);
     }
 
-    let sheet_name = get_arg_value(&args, "--sheet")
-        .or_else(|| get_arg_value(&args, "-s"))
-        .or_else(|| cfg.as_ref().and_then(|c| c.sheet.clone()))
-        .unwrap_or_else(|| "Sheet1".to_string());
-
-    // Input mode precedence: --array > --ndjson > config.ndjson > false
-    let force_array = has_flag(&args, "--array");
-    let force_ndjson = has_flag(&args, "--ndjson");
-    let mut ndjson_mode = if force_array {
-        false
-    } else if force_ndjson {
-        true
-    } else {
-        cfg.as_ref().and_then(|c| c.ndjson).unwrap_or(false)
-    };
+    // stdin can feed only one job
+    let cli_input = get_arg_value(&args, "--input");
+    let stdin_jobs = jobs
+        .iter()
+        .filter(|c| cli_input.is_none() && c.as_ref().and_then(|c| c.input.as_ref()).is_none())
+        .count();
+    if stdin_jobs > 1 {
+        return Err(format!("{} jobs read stdin; give each job its own `input` file", stdin_jobs).into());
+    }
 
-    // PKs
-    let pk_keys: Vec<String> = if let Some(s) = get_arg_value(&args, "--pk").or_else(|| get_arg_value(&args, "-k")) {
-        split_csv(&s)
-    } else if let Some(c) = &cfg {
-        c.pk.clone().unwrap_or_default()
+    // Read the workbook once (umya to write, calamine for typed values) and save once
+    let out_exists = Path::new(&out_path).exists();
+    let mut book = if out_exists {
+        umya::reader::xlsx::read(Path::new(&out_path))?
     } else {
-        Vec::new()
+        umya::new_file()
     };
-
-    // pk_first toggle (default true). CLI supports --pk-first / --no-pk-first
-    let pk_first = if has_flag(&args, "--no-pk-first") {
-        false
-    } else if has_flag(&args, "--pk-first") {
-        true
+    let mut existing = if out_exists {
+        match open_workbook_auto(&out_path) {
+            Ok(wb) => Some(wb),
+            Err(e) => {
+                eprintln!(
+                    "Warning: couldn't read existing workbook `{}` ({}). Recreating sheet content.",
+                    out_path, e
+                );
+                None
+            }
+        }
     } else {
-        cfg.as_ref().and_then(|c| c.pk_first).unwrap_or(true)
+        None
     };
 
-    // ---------------- include filters ----------------
-    let include_flag_exact_cli = has_flag(&args, "--include") || has_flag(&args, "-i");
-    let mut include_exact: HashSet<String> = cfg
-        .as_ref()
-        .and_then(|c| c.include.clone())
-        .unwrap_or_default()
-        .into_iter()
-        .collect();
-    if let Some(s) = get_arg_value(&args, "--include").or_else(|| get_arg_value(&args, "-i")) {
-        include_exact.extend(split_csv(&s).into_iter());
+    let dry_run = has_flag(&args, "--dry-run");
+    let mut claimed: HashMap<String, SheetUse> = HashMap::new();
+    let mut reports: Vec<Value> = Vec::new();
+    for cfg in &jobs {
+        let ctx = JobContext {
+            out_path: &out_path,
+            dry_run,
+            jobs: jobs.len(),
+            split: None,
+        };
+        let opts = JobOptions::resolve(&args, cfg)?;
+        run_job(&opts, &ctx, &mut book, existing.as_mut(), &mut claimed, &mut reports)?;
     }
 
-    let include_flag_regex_cli = has_flag(&args, "--include-regex");
-    let mut include_regexes: Vec<Regex> = Vec::new();
-    if let Some(c) = &cfg {
-        for pat in c.include_regex.clone().unwrap_or_default() {
-            include_regexes.push(
-                Regex::new(&format!("^(?:{})$", pat))
-                    .map_err(|e| format!("Invalid regex in config `{}`: {}", pat, e))?,
-            );
+    if dry_run {
+        // --report json: one object, or an array of them with several jobs
+        match reports.len() {
+            0 => {}
+            1 => println!("{}", serde_json::to_string_pretty(&reports[0])?),
+            _ => println!("{}", serde_json::to_string_pretty(&reports)?),
         }
+        return Ok(());
     }
-    if let Some(s) = get_arg_value(&args, "--include-regex") {
-        for pat in split_csv(&s).into_iter().filter(|p| !p.is_empty()) {
-            include_regexes.push(
-                Regex::new(&format!("^(?:{})$", pat))
-                    .map_err(|e| format!("Invalid regex `{}`: {}", pat, e))?,
-            );
+
+    // Save back to same file (styles remain intact)
+    umya::writer::xlsx::write(&book, Path::new(&out_path))?;
+    Ok(())
+}
+
+// Shared by every job of one invocation
+#[derive(Clone, Copy)]
+struct JobContext<'a> {
+    out_path: &'a str,
+    dry_run: bool,
+    jobs: usize,                   // [[job]] entries in this run
+    split: Option<&'a SplitSheet>, // sheet_by: one generated sheet and its input rows
+}
+
+// What a sheet is to this run; only the History sheet may be shared
+#[derive(Clone, Copy, PartialEq)]
+enum SheetUse {
+    Data,
+    Snapshot,
+    History,
+}
+
+impl SheetUse {
+    fn describe(self) -> &'static str {
+        match self {
+            SheetUse::Data => "a data sheet",
+            SheetUse::Snapshot => "a snapshot sheet",
+            SheetUse::History => "a History sheet",
         }
     }
+}
 
-    let include_flag_substr_cli = has_flag(&args, "--include-substr");
-    let mut include_substrs: Vec<String> = cfg
-        .as_ref()
-        .and_then(|c| c.include_substr.clone())
-        .unwrap_or_default();
-    if let Some(s) = get_arg_value(&args, "--include-substr") {
-        include_substrs.extend(split_csv(&s));
+// Record that this run writes `name` (sheet names ignore case in Excel)
+fn claim_sheet(
+    claimed: &mut HashMap<String, SheetUse>,
+    name: &str,
+    usage: SheetUse,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match claimed.get(&name.to_lowercase()).copied() {
+        None => {
+            claimed.insert(name.to_lowercase(), usage);
+            Ok(())
+        }
+        Some(SheetUse::History) if usage == SheetUse::History => Ok(()),
+        Some(SheetUse::Data) if usage == SheetUse::Data => {
+            Err(format!("Sheet `{}` is written by more than one job", name).into())
+        }
+        Some(other) => Err(format!(
+            "Sheet `{}` would be both {} and {}",
+            name,
+            other.describe(),
+            usage.describe()
+        )
+        .into()),
     }
+}
 
-    let include_active = include_flag_exact_cli
-        || include_flag_regex_cli
-        || include_flag_substr_cli
-        || !include_exact.is_empty()
-        || !include_regexes.is_empty()
-        || !include_substrs.is_empty();
+// ---------------- Job options ----------------
 
-    // ---------------- ordering controls ----------------
-    let order_exact: Vec<String> = if let Some(s) = get_arg_value(&args, "--order") {
-        split_csv(&s)
-    } else if let Some(c) = &cfg {
-        c.order.clone().unwrap_or_default()
-    } else {
-        Vec::new()
-    };
+// One job's settings, resolved CLI > job > top-level config > default
+struct JobOptions {
+    sheet: String,            // sheet name, or the name template with sheet_by
+    sheet_by: Option<String>, // one sheet per value of this column
+    input_path: Option<String>,
+    ndjson_mode: bool,
+    root: Option<String>, // JSON Pointer to the record list
+    flatten: Option<Flatten>,
+    explode: Vec<Explode>,
+    collapse: Vec<Collapse>,
 
-    let order_regex_raw: Vec<String> = if let Some(s) = get_arg_value(&args, "--order-regex") {
-        split_csv(&s)
-    } else if let Some(c) = &cfg {
-        c.order_regex.clone().unwrap_or_default()
-    } else {
-        Vec::new()
-    };
-    let order_regex: Vec<Regex> = order_regex_raw
-        .iter()
-        .map(|pat| Regex::new(&format!("^(?:{})$", pat))
-            .map_err(|e| format!("Invalid --order-regex `{}`: {}", pat, e)))
-        .collect::<Result<_, _>>()?;
-
-    let order_substr: Vec<String> = if let Some(s) = get_arg_value(&args, "--order-substr") {
-        split_csv(&s)
-    } else if let Some(c) = &cfg {
-        c.order_substr.clone().unwrap_or_default()
-    } else {
-        Vec::new()
-    };
+    // PK matching and merging
+    pk_keys: Vec<String>,     // incl. the element columns of exploded paths
+    pk_optional: Vec<String>, // PK columns that may be empty
+    pk_norms: HashMap<String, PkNorm>,
+    dup_policy: String,
+    merge_mode: String,
+    protected: HashSet<String>,
+    pk_first: bool,
 
-    let order_rest: String = if let Some(s) = get_arg_value(&args, "--order-rest") {
-        s
-    } else if let Some(c) = &cfg {
-        c.order_rest.clone().unwrap_or_else(|| "existing".to_string())
-    } else {
-        "existing".to_string()
-    };
-    let order_rest_mode = order_rest.to_lowercase();
-
-    // ------------- hyperlinks (config + CLI) -------------
-    let mut hyperlink_map: HashMap<String, String> = cfg
-        .as_ref()
-        .map(|c| c.hyperlink.clone())
-        .unwrap_or_default();
-
-    if let Some(link_arg) = get_arg_value(&args, "--link") {
-        // format: col=BASE[,col2=BASE2,...]
-        for part in split_csv(&link_arg) {
-            if let Some((k, v)) = split_once_eq(&part) {
-                hyperlink_map.insert(k.to_string(), v.to_string());
-            } else {
-                eprintln!("Ignoring malformed --link mapping: `{}` (expected col=BASE)", part);
+    // column selection and order
+    include_active: bool,
+    include_exact: HashSet<String>,
+    include_regexes: Vec<Regex>,
+    include_substrs: Vec<String>,
+    exclude_exact: HashSet<String>,
+    exclude_regexes: Vec<Regex>,
+    exclude_substrs: Vec<String>,
+    order_exact: Vec<String>,
+    order_regex: Vec<Regex>,
+    order_substr: Vec<String>,
+    order_rest: String, // existing|alpha|none
+    clear_mode: String,
+
+    // presentation
+    hyperlink_map: HashMap<String, LinkTemplate>,
+    link_texts: HashMap<String, String>, // key -> display text template
+    link_mode: String,
+    labels: HashMap<String, String>,
+    types: HashMap<String, ColumnType>,
+    auto_dates: bool,
+    tz_mode: String,
+    date_format: String,
+    datetime_format: String,
+    formats: HashMap<String, ColumnFormat>,
+    format_existing: bool,
+    template_row: Option<u32>,
+    inherit_row_style: bool,
+    table: Option<TableSpec>,
+    freeze_spec: Option<String>,
+    autofilter: Option<bool>,
+    auto_width: Option<(f64, f64)>,
+
+    // sync, audit, change tracking, snapshots
+    sync_mode: String,
+    archive_sheet: String,
+    status_column: String,
+    status_removed: String,
+    strike_removed: bool,
+    audit: bool,
+    audit_position: String,
+    track_changes: bool,
+    changed_fill: String,
+    new_fill: String,
+    changes_sheet: String,
+    snapshot: Option<SnapshotSpec>,
+    report_format: String,
+}
+
+impl JobOptions {
+    fn resolve(args: &[String], cfg: &Option<ConfigFile>) -> Result<Self, Box<dyn std::error::Error>> {
+        // One sheet per value of `sheet_by`; `sheet` is then the name template
+        let sheet_by = get_arg_value(args, "--sheet-by")
+            .or_else(|| cfg.as_ref().and_then(|c| c.sheet_by.clone()));
+        let sheet = get_arg_value(args, "--sheet")
+            .or_else(|| get_arg_value(args, "-s"))
+            .or_else(|| cfg.as_ref().and_then(|c| c.sheet.clone()))
+            .unwrap_or_else(|| if sheet_by.is_some() { "{value}" } else { "Sheet1" }.to_string());
+        if sheet_by.is_some() && !sheet.contains("{value}") {
+            return Err(format!("Sheet name template `{}` needs a {{value}} placeholder", sheet).into());
+        }
+        let input_path = get_arg_value(args, "--input")
+            .or_else(|| cfg.as_ref().and_then(|c| c.input.clone()));
+
+        // Input mode precedence: --array > --ndjson > config.ndjson > false
+        let force_array = has_flag(args, "--array");
+        let force_ndjson = has_flag(args, "--ndjson");
+        let ndjson_mode = if force_array {
+            false
+        } else if force_ndjson {
+            true
+        } else {
+            cfg.as_ref().and_then(|c| c.ndjson).unwrap_or(false)
+        };
+
+        // Record root inside an envelope, e.g. Jira's {"issues": [...]}
+        let root: Option<String> = get_arg_value(args, "--root")
+            .or_else(|| cfg.as_ref().and_then(|c| c.root.clone()))
+            .map(|r| root_to_pointer(&r));
+
+        // Flattening: --flatten / --no-flatten > config.flatten > false
+        let flatten_enabled = if has_flag(args, "--no-flatten") {
+            false
+        } else if has_flag(args, "--flatten") {
+            true
+        } else {
+            cfg.as_ref().and_then(|c| c.flatten).unwrap_or(false)
+        };
+        let flatten = if flatten_enabled {
+            let sep = get_arg_value(args, "--flatten-sep")
+                .or_else(|| cfg.as_ref().and_then(|c| c.flatten_sep.clone()))
+                .unwrap_or_else(|| ".".to_string());
+            let max_depth = match get_arg_value(args, "--flatten-depth") {
+                Some(s) => Some(
+                    s.parse::<usize>()
+                        .map_err(|e| format!("Invalid --flatten-depth `{}`: {}", s, e))?,
+                ),
+                None => cfg.as_ref().and_then(|c| c.flatten_depth),
+            };
+            if max_depth == Some(0) {
+                return Err("flatten depth must be at least 1".into());
+            }
+            Some(Flatten { sep, max_depth })
+        } else {
+            None
+        };
+
+        // Explode arrays into rows (config + CLI)
+        let mut explode_key_map: HashMap<String, String> = cfg
+            .as_ref()
+            .map(|c| c.explode_key.clone())
+            .unwrap_or_default();
+        if let Some(arg) = get_arg_value(args, "--explode-key") {
+            // format: path=child[,path2=child2,...]
+            for part in split_csv(&arg) {
+                if let Some((k, v)) = split_once_eq(&part) {
+                    explode_key_map.insert(k.to_string(), v.to_string());
+                } else {
+                    eprintln!("Ignoring malformed --explode-key mapping: `{}` (expected path=child)", part);
+                }
             }
         }
-    }
+        let explode_paths: Vec<String> = if let Some(s) = get_arg_value(args, "--explode") {
+            split_csv(&s)
+        } else if let Some(c) = &cfg {
+            c.explode.clone().unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+        let key_sep = flatten.as_ref().map(|f| f.sep.as_str()).unwrap_or(".");
+        let explode: Vec<Explode> = explode_paths
+            .iter()
+            .map(|name| Explode::new(name, explode_key_map.get(name).cloned(), key_sep))
+            .collect();
 
-    // -------- read stdin --------
-    let mut input = String::new();
-    io::stdin().read_to_string(&mut input)?;
+        // Collapse indexed columns (config + CLI)
+        let collapse_sep = get_arg_value(args, "--collapse-sep")
+            .or_else(|| cfg.as_ref().and_then(|c| c.collapse_sep.clone()))
+            .map(|s| unescape_sep(&s))
+            .unwrap_or_else(|| ", ".to_string());
+        let mut collapse_raw: Vec<(String, String, String)> = Vec::new(); // (pattern, column, sep)
+        if let Some(c) = &cfg {
+            for (pat, rule) in &c.collapse {
+                let (column, sep) = match rule {
+                    CollapseRule::Column(col) => (col.clone(), collapse_sep.clone()),
+                    CollapseRule::Full { column, sep } => (
+                        column.clone(),
+                        sep.as_deref().map(unescape_sep).unwrap_or_else(|| collapse_sep.clone()),
+                    ),
+                };
+                collapse_raw.push((pat.clone(), column, sep));
+            }
+            // HashMap order is arbitrary; keep rule application stable
+            collapse_raw.sort();
+        }
+        if let Some(arg) = get_arg_value(args, "--collapse") {
+            // format: pattern=column[,pattern2=column2,...]
+            for part in split_csv(&arg) {
+                if let Some((k, v)) = split_once_eq(&part) {
+                    collapse_raw.push((k.to_string(), v.to_string(), collapse_sep.clone()));
+                } else {
+                    eprintln!("Ignoring malformed --collapse mapping: `{}` (expected pattern=column)", part);
+                }
+            }
+        }
+        let collapse: Vec<Collapse> = collapse_raw
+            .into_iter()
+            .map(|(pat, column, sep)| {
+                Regex::new(&format!("^(?:{})$", pat))
+                    .map(|re| Collapse { re, column, sep })
+                    .map_err(|e| format!("Invalid collapse pattern `{}`: {}", pat, e))
+            })
+            .collect::<Result<_, _>>()?;
 
-    // If in NDJSON mode but the payload clearly looks like a JSON array, switch modes.
-    let looks_like_array = input.trim_start().starts_with('[');
-    if ndjson_mode && looks_like_array {
-        eprintln!("Note: input looks like a JSON array; overriding NDJSON and parsing as array.");
-        ndjson_mode = false;
-    }
+        // PKs
+        let mut pk_keys: Vec<String> = if let Some(s) = get_arg_value(args, "--pk").or_else(|| get_arg_value(args, "-k")) {
+            split_csv(&s)
+        } else if let Some(c) = &cfg {
+            c.pk.clone().unwrap_or_default()
+        } else {
+            Vec::new()
+        };
 
-    let mut new_rows: Vec<HashMap<String, Value>> = if ndjson_mode {
-        parse_ndjson(&input)?
-    } else {
-        parse_json_array(&input)?
-    };
+        // PK normalization for matching (config + CLI)
+        let mut pk_norms: HashMap<String, PkNorm> = HashMap::new();
+        if let Some(c) = &cfg {
+            for (k, opts) in &c.pk_normalize {
+                pk_norms.insert(k.clone(), PkNorm::parse(&opts.join("+"))?);
+            }
+        }
+        if let Some(arg) = get_arg_value(args, "--pk-normalize") {
+            // format: col=trim+casefold[,col2=numeric,...]; col may be `*`
+            for part in split_csv(&arg) {
+                if let Some((k, v)) = split_once_eq(&part) {
+                    pk_norms.insert(k.to_string(), PkNorm::parse(v)?);
+                } else {
+                    eprintln!("Ignoring malformed --pk-normalize mapping: `{}` (expected col=trim+casefold)", part);
+                }
+            }
+        }
 
-    // -------- existing workbook values --------
-    let out_exists = Path::new(&out_path).exists();
-    let (mut existing_headers, mut existing_rows) = if out_exists {
-        match read_existing_xlsx_values(&out_path, &sheet_name) {
-            Ok(data) => data,
-            Err(e) => {
-                eprintln!(
-                    "Warning: couldn't read existing workbook `{}` ({}). Recreating sheet content.",
-                    out_path, e
+        // Rows sharing a PK (in the input or already in the sheet)
+        let dup_policy = get_arg_value(args, "--duplicates")
+            .or_else(|| cfg.as_ref().and_then(|c| c.duplicates.clone()))
+            .unwrap_or_else(|| "last-wins".to_string())
+            .to_lowercase();
+        if !matches!(dup_policy.as_str(), "error" | "first-wins" | "last-wins" | "keep-all") {
+            return Err(format!(
+                "Invalid duplicates policy `{}` (expected error|first-wins|last-wins|keep-all)",
+                dup_policy
+            )
+            .into());
+        }
+
+        // Merge strategy for matched rows + protected (hand-maintained) columns
+        let merge_mode = get_arg_value(args, "--merge")
+            .or_else(|| cfg.as_ref().and_then(|c| c.merge.clone()))
+            .unwrap_or_else(|| "replace".to_string())
+            .to_lowercase();
+        if !matches!(merge_mode.as_str(), "replace" | "patch" | "patch-non-null") {
+            return Err(format!("Invalid merge strategy `{}` (expected replace|patch|patch-non-null)", merge_mode).into());
+        }
+        let mut protected: HashSet<String> = cfg
+            .as_ref()
+            .and_then(|c| c.protect.clone())
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+        if let Some(s) = get_arg_value(args, "--protect") {
+            protected.extend(split_csv(&s));
+        }
+
+        // pk_first toggle (default true). CLI supports --pk-first / --no-pk-first
+        let pk_first = if has_flag(args, "--no-pk-first") {
+            false
+        } else if has_flag(args, "--pk-first") {
+            true
+        } else {
+            cfg.as_ref().and_then(|c| c.pk_first).unwrap_or(true)
+        };
+
+        // Exploded rows share the parent's PK, so each exploded path adds its element
+        // column (index or child key) to the composite PK. It may be empty for records
+        // whose array was empty/missing, hence "optional".
+        let mut pk_optional: Vec<String> = Vec::new();
+        if !pk_keys.is_empty() {
+            for ex in &explode {
+                if !pk_keys.contains(&ex.pk_column) {
+                    pk_optional.push(ex.pk_column.clone());
+                    pk_keys.push(ex.pk_column.clone());
+                }
+            }
+        }
+
+        // ---------------- include filters ----------------
+        let include_flag_exact_cli = has_flag(args, "--include") || has_flag(args, "-i");
+        let mut include_exact: HashSet<String> = cfg
+            .as_ref()
+            .and_then(|c| c.include.clone())
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+        if let Some(s) = get_arg_value(args, "--include").or_else(|| get_arg_value(args, "-i")) {
+            include_exact.extend(split_csv(&s));
+        }
+
+        let include_flag_regex_cli = has_flag(args, "--include-regex");
+        let mut include_regexes: Vec<Regex> = Vec::new();
+        if let Some(c) = &cfg {
+            for pat in c.include_regex.clone().unwrap_or_default() {
+                include_regexes.push(
+                    Regex::new(&format!("^(?:{})$", pat))
+                        .map_err(|e| format!("Invalid regex in config `{}`: {}", pat, e))?,
+                );
+            }
+        }
+        if let Some(s) = get_arg_value(args, "--include-regex") {
+            for pat in split_csv(&s).into_iter().filter(|p| !p.is_empty()) {
+                include_regexes.push(
+                    Regex::new(&format!("^(?:{})$", pat))
+                        .map_err(|e| format!("Invalid regex `{}`: {}", pat, e))?,
                 );
-                (Vec::new(), Vec::new())
             }
         }
-    } else {
-        (Vec::new(), Vec::new())
-    };
 
-    // -------- merge by PK --------
-    if !pk_keys.is_empty() {
-        let mut index: HashMap<String, usize> = HashMap::new();
-        for (i, row) in existing_rows.iter().enumerate() {
-            if let Some(key) = composite_pk(row, &pk_keys) {
-                index.insert(key, i);
+        let include_flag_substr_cli = has_flag(args, "--include-substr");
+        let mut include_substrs: Vec<String> = cfg
+            .as_ref()
+            .and_then(|c| c.include_substr.clone())
+            .unwrap_or_default();
+        if let Some(s) = get_arg_value(args, "--include-substr") {
+            include_substrs.extend(split_csv(&s));
+        }
+
+        let include_active = include_flag_exact_cli
+            || include_flag_regex_cli
+            || include_flag_substr_cli
+            || !include_exact.is_empty()
+            || !include_regexes.is_empty()
+            || !include_substrs.is_empty();
+
+        // ---------------- exclude filters ----------------
+        let mut exclude_exact: HashSet<String> = cfg
+            .as_ref()
+            .and_then(|c| c.exclude.clone())
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+        if let Some(s) = get_arg_value(args, "--exclude") {
+            exclude_exact.extend(split_csv(&s));
+        }
+
+        let mut exclude_regexes: Vec<Regex> = Vec::new();
+        if let Some(c) = &cfg {
+            for pat in c.exclude_regex.clone().unwrap_or_default() {
+                exclude_regexes.push(
+                    Regex::new(&format!("^(?:{})$", pat))
+                        .map_err(|e| format!("Invalid regex in config `{}`: {}", pat, e))?,
+                );
             }
         }
-        for row in new_rows.drain(..) {
-            if let Some(key) = composite_pk(&row, &pk_keys) {
-                if let Some(&idx) = index.get(&key) {
-                    existing_rows[idx] = row;
+        if let Some(s) = get_arg_value(args, "--exclude-regex") {
+            for pat in split_csv(&s).into_iter().filter(|p| !p.is_empty()) {
+                exclude_regexes.push(
+                    Regex::new(&format!("^(?:{})$", pat))
+                        .map_err(|e| format!("Invalid regex `{}`: {}", pat, e))?,
+                );
+            }
+        }
+
+        let mut exclude_substrs: Vec<String> = cfg
+            .as_ref()
+            .and_then(|c| c.exclude_substr.clone())
+            .unwrap_or_default();
+        if let Some(s) = get_arg_value(args, "--exclude-substr") {
+            exclude_substrs.extend(split_csv(&s));
+        }
+
+        // ---------------- ordering controls ----------------
+        let order_exact: Vec<String> = if let Some(s) = get_arg_value(args, "--order") {
+            split_csv(&s)
+        } else if let Some(c) = &cfg {
+            c.order.clone().unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+
+        let order_regex_raw: Vec<String> = if let Some(s) = get_arg_value(args, "--order-regex") {
+            split_csv(&s)
+        } else if let Some(c) = &cfg {
+            c.order_regex.clone().unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+        let order_regex: Vec<Regex> = order_regex_raw
+            .iter()
+            .map(|pat| Regex::new(&format!("^(?:{})$", pat))
+                .map_err(|e| format!("Invalid --order-regex `{}`: {}", pat, e)))
+            .collect::<Result<_, _>>()?;
+
+        let order_substr: Vec<String> = if let Some(s) = get_arg_value(args, "--order-substr") {
+            split_csv(&s)
+        } else if let Some(c) = &cfg {
+            c.order_substr.clone().unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+
+        let order_rest: String = if let Some(s) = get_arg_value(args, "--order-rest") {
+            s
+        } else if let Some(c) = &cfg {
+            c.order_rest.clone().unwrap_or_else(|| "existing".to_string())
+        } else {
+            "existing".to_string()
+        }
+        .to_lowercase();
+
+        // ---------------- stale cells ----------------
+        let clear_mode = get_arg_value(args, "--clear")
+            .or_else(|| cfg.as_ref().and_then(|c| c.clear.clone()))
+            .unwrap_or_else(|| "none".to_string())
+            .to_lowercase();
+        if !matches!(clear_mode.as_str(), "none" | "values" | "remove") {
+            return Err(format!("Invalid clear mode `{}` (expected none|values|remove)", clear_mode).into());
+        }
+
+        // ------------- hyperlinks (config + CLI) -------------
+        let mut hyperlink_map: HashMap<String, LinkTemplate> = cfg
+            .as_ref()
+            .map(|c| {
+                c.hyperlink
+                    .iter()
+                    .map(|(k, rule)| (k.clone(), LinkTemplate::from_rule(rule)))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        if let Some(link_arg) = get_arg_value(args, "--link") {
+            // format: col=BASE_OR_TEMPLATE[,col2=BASE2,...]
+            for part in split_csv(&link_arg) {
+                if let Some((k, v)) = split_once_eq(&part) {
+                    hyperlink_map.insert(k.to_string(), LinkTemplate::new(v, None, true));
                 } else {
-                    index.insert(key, existing_rows.len());
-                    existing_rows.push(row);
+                    eprintln!("Ignoring malformed --link mapping: `{}` (expected col=BASE)", part);
                 }
-            } else {
-                existing_rows.push(row);
             }
         }
-    } else {
-        existing_rows.extend(new_rows.drain(..));
-    }
-
-    // -------- union of keys --------
-    let mut all_keys: BTreeSet<String> = BTreeSet::new();
-    for r in &existing_rows {
-        for k in r.keys() {
-            all_keys.insert(k.clone());
+        // a display text replaces the value in the cell, so it must let us read the value back
+        let mut link_texts: HashMap<String, String> = HashMap::new();
+        for (key, tpl) in &hyperlink_map {
+            if let Some(text) = &tpl.text {
+                if link_text_re(text, key).is_none() {
+                    return Err(format!(
+                        "Link text `{}` for `{}` needs a {{value}} placeholder (the cell shows the text instead of the value)",
+                        text, key
+                    )
+                    .into());
+                }
+                link_texts.insert(key.clone(), text.clone());
+            }
         }
-    }
 
-    // clean empty header cells
-    existing_headers.retain(|h| !h.trim().is_empty());
+        // formula (HYPERLINK()) or native (cell hyperlink relationship)
+        let link_mode = get_arg_value(args, "--link-mode")
+            .or_else(|| cfg.as_ref().and_then(|c| c.link_mode.clone()))
+            .unwrap_or_else(|| "formula".to_string())
+            .to_lowercase();
+        if !matches!(link_mode.as_str(), "formula" | "native") {
+            return Err(format!("Invalid link mode `{}` (expected formula|native)", link_mode).into());
+        }
 
-    // inclusion predicate
-    let pk_set: HashSet<&str> = pk_keys.iter().map(|s| s.as_str()).collect();
-    let key_allowed = |key: &str| -> bool {
-        if pk_set.contains(key) {
-            return true; // PKs always included even if include filters present
+        // ------------- header labels (config + CLI) -------------
+        let mut labels: HashMap<String, String> = cfg
+            .as_ref()
+            .map(|c| c.labels.clone())
+            .unwrap_or_default();
+        if let Some(arg) = get_arg_value(args, "--label") {
+            // format: key=Label[,key2=Label2,...]
+            for part in split_csv(&arg) {
+                if let Some((k, v)) = split_once_eq(&part) {
+                    labels.insert(k.to_string(), v.to_string());
+                } else {
+                    eprintln!("Ignoring malformed --label mapping: `{}` (expected key=Label)", part);
+                }
+            }
         }
-        if include_active {
-            if include_exact.contains(key) {
-                return true;
+        let mut label_owner: HashMap<&str, &str> = HashMap::new();
+        for (k, l) in &labels {
+            if let Some(other) = label_owner.insert(l.as_str(), k.as_str()) {
+                return Err(format!("Label `{}` is used for both `{}` and `{}`", l, other, k).into());
             }
-            if !include_regexes.is_empty() && include_regexes.iter().any(|re| re.is_match(key)) {
-                return true;
+        }
+
+        // ------------- column types / dates (config + CLI) -------------
+        let mut types: HashMap<String, ColumnType> = HashMap::new();
+        if let Some(c) = &cfg {
+            for (k, t) in &c.types {
+                types.insert(k.clone(), parse_column_type(t)?);
             }
-            if !include_substrs.is_empty() && include_substrs.iter().any(|sub| key.contains(sub)) {
-                return true;
+        }
+        if let Some(arg) = get_arg_value(args, "--type") {
+            // format: key=type[,key2=type2,...]
+            for part in split_csv(&arg) {
+                if let Some((k, v)) = split_once_eq(&part) {
+                    types.insert(k.to_string(), parse_column_type(v)?);
+                } else {
+                    eprintln!("Ignoring malformed --type mapping: `{}` (expected key=type)", part);
+                }
             }
+        }
+        let auto_dates = if has_flag(args, "--no-auto-dates") {
+            false
+        } else if has_flag(args, "--auto-dates") {
+            true
+        } else {
+            cfg.as_ref().and_then(|c| c.auto_dates).unwrap_or(false)
+        };
+        let tz_mode = get_arg_value(args, "--timezone")
+            .or_else(|| cfg.as_ref().and_then(|c| c.timezone.clone()))
+            .unwrap_or_else(|| "drop".to_string())
+            .to_lowercase();
+        if !matches!(tz_mode.as_str(), "utc" | "local" | "drop") {
+            return Err(format!("Invalid timezone mode `{}` (expected utc|local|drop)", tz_mode).into());
+        }
+        let date_format = cfg
+            .as_ref()
+            .and_then(|c| c.date_format.clone())
+            .unwrap_or_else(|| "yyyy-mm-dd".to_string());
+        let datetime_format = cfg
+            .as_ref()
+            .and_then(|c| c.datetime_format.clone())
+            .unwrap_or_else(|| "yyyy-mm-dd hh:mm:ss".to_string());
+
+        // ------------- per-column formats (config) -------------
+        let formats: HashMap<String, ColumnFormat> = cfg
+            .as_ref()
+            .map(|c| c.format.clone())
+            .unwrap_or_default();
+        for (key, fmt) in &formats {
+            fmt.validate().map_err(|e| format!("[format.\"{}\"]: {}", key, e))?;
+        }
+        let format_existing = if has_flag(args, "--no-format-existing") {
             false
+        } else if has_flag(args, "--format-existing") {
+            true
         } else {
+            cfg.as_ref().and_then(|c| c.format_existing).unwrap_or(false)
+        };
+
+        // Appended rows inherit the style of the last data row (or a template row)
+        let template_row: Option<u32> = match get_arg_value(args, "--template-row") {
+            Some(s) => Some(
+                s.parse::<u32>()
+                    .map_err(|e| format!("Invalid --template-row `{}`: {}", s, e))?,
+            ),
+            None => cfg.as_ref().and_then(|c| c.template_row),
+        };
+        let inherit_row_style = if has_flag(args, "--no-inherit-row-style") {
+            false
+        } else if has_flag(args, "--inherit-row-style") || template_row.is_some() {
             true
+        } else {
+            cfg.as_ref().and_then(|c| c.inherit_row_style).unwrap_or(false)
+        };
+
+        // Excel Table (ListObject) over the written range
+        let mut table: Option<TableSpec> = cfg.as_ref().and_then(|c| c.table.as_ref()).map(TableSpec::from_rule);
+        if let Some(name) = get_arg_value(args, "--table") {
+            let mut spec = table.take().unwrap_or_else(|| TableSpec::from_rule(&TableRule::Name(String::new())));
+            spec.name = name;
+            table = Some(spec);
+        }
+        if let Some(style) = get_arg_value(args, "--table-style") {
+            match table.as_mut() {
+                Some(spec) => spec.style = Some(style),
+                None => eprintln!("Ignoring --table-style without --table"),
+            }
+        }
+        if let Some(spec) = &table {
+            validate_table_name(&spec.name)?;
         }
-    };
 
-    // universe: existing headers (filtered) + remaining keys (natural sorted), all filtered by key_allowed
-    let universe_existing: Vec<String> = existing_headers
-        .iter()
-        .filter(|h| !pk_set.contains(h.as_str()) && key_allowed(h))
-        .cloned()
-        .collect();
+        // Sheet view: freeze panes, autofilter, column widths
+        let freeze_spec: Option<String> = if has_flag(args, "--no-freeze") {
+            None
+        } else {
+            get_arg_value(args, "--freeze").or_else(|| cfg.as_ref().and_then(|c| c.freeze.clone()))
+        };
+        if let Some(spec) = &freeze_spec {
+            resolve_freeze(spec, &[], &[])?; // validate before reading input
+        }
+        // None: leave whatever filter the sheet has; Some(false) removes it
+        let autofilter = if has_flag(args, "--no-autofilter") {
+            Some(false)
+        } else if has_flag(args, "--autofilter") {
+            Some(true)
+        } else {
+            cfg.as_ref().and_then(|c| c.autofilter)
+        };
+        let auto_width_enabled = if has_flag(args, "--no-auto-width") {
+            false
+        } else if has_flag(args, "--auto-width") {
+            true
+        } else {
+            cfg.as_ref().and_then(|c| c.auto_width).unwrap_or(false)
+        };
+        let min_width = match get_arg_value(args, "--min-width") {
+            Some(s) => s.parse::<f64>().map_err(|e| format!("Invalid --min-width `{}`: {}", s, e))?,
+            None => cfg.as_ref().and_then(|c| c.min_width).unwrap_or(8.0),
+        };
+        let max_width = match get_arg_value(args, "--max-width") {
+            Some(s) => s.parse::<f64>().map_err(|e| format!("Invalid --max-width `{}`: {}", s, e))?,
+            None => cfg.as_ref().and_then(|c| c.max_width).unwrap_or(60.0),
+        };
+        if !(min_width > 0.0 && min_width <= max_width && max_width <= 255.0) {
+            return Err(format!("Invalid column widths: need 0 < min ({}) <= max ({}) <= 255", min_width, max_width).into());
+        }
+        let auto_width = auto_width_enabled.then_some((min_width, max_width));
 
-    let mut remaining_from_all: Vec<String> = all_keys
-        .iter()
-        .filter(|k| !pk_set.contains(k.as_str()) && key_allowed(k))
-        .cloned()
-        .collect();
-    remaining_from_all.retain(|k| !universe_existing.contains(k));
-    remaining_from_all.sort_by(natural_cmp);
+        // Sync: what happens to existing rows whose PK is absent from this run
+        let sync_mode = get_arg_value(args, "--sync")
+            .or_else(|| cfg.as_ref().and_then(|c| c.sync.clone()))
+            .unwrap_or_else(|| "none".to_string())
+            .to_lowercase();
+        if !matches!(sync_mode.as_str(), "none" | "remove" | "archive" | "mark") {
+            return Err(format!("Invalid sync mode `{}` (expected none|remove|archive|mark)", sync_mode).into());
+        }
+        if sync_mode != "none" && pk_keys.is_empty() {
+            return Err("--sync needs a primary key (--pk)".into());
+        }
+        let archive_sheet = get_arg_value(args, "--archive-sheet")
+            .or_else(|| cfg.as_ref().and_then(|c| c.archive_sheet.clone()))
+            .unwrap_or_else(|| "Archive".to_string());
+        let status_column = get_arg_value(args, "--status-column")
+            .or_else(|| cfg.as_ref().and_then(|c| c.status_column.clone()))
+            .unwrap_or_else(|| "_status".to_string());
+        let status_removed = cfg
+            .as_ref()
+            .and_then(|c| c.status_removed.clone())
+            .unwrap_or_else(|| "removed".to_string());
+        let strike_removed = if has_flag(args, "--no-strike-removed") {
+            false
+        } else if has_flag(args, "--strike-removed") {
+            true
+        } else {
+            cfg.as_ref().and_then(|c| c.strike_removed).unwrap_or(false)
+        };
 
-    let mut universe: Vec<String> = Vec::new();
-    universe.extend(universe_existing.into_iter());
-    universe.extend(remaining_from_all.into_iter());
+        // Audit columns
+        let audit = if has_flag(args, "--no-audit") {
+            false
+        } else if has_flag(args, "--audit") {
+            true
+        } else {
+            cfg.as_ref().and_then(|c| c.audit).unwrap_or(false)
+        };
+        let audit_position = get_arg_value(args, "--audit-position")
+            .or_else(|| cfg.as_ref().and_then(|c| c.audit_position.clone()))
+            .unwrap_or_else(|| "end".to_string())
+            .to_lowercase();
+        if !matches!(audit_position.as_str(), "first" | "end") {
+            return Err(format!("Invalid audit position `{}` (expected first|end)", audit_position).into());
+        }
 
-    // ---------- build final columns ----------
-    let mut columns: Vec<String> = Vec::new();
-    let mut seen: HashSet<String> = HashSet::new();
+        // Change tracking
+        let track_changes = if has_flag(args, "--no-track-changes") {
+            false
+        } else if has_flag(args, "--track-changes") {
+            true
+        } else {
+            cfg.as_ref().and_then(|c| c.track_changes).unwrap_or(false)
+        };
+        let changed_fill = argb(
+            &get_arg_value(args, "--changed-fill")
+                .or_else(|| cfg.as_ref().and_then(|c| c.changed_fill.clone()))
+                .unwrap_or_else(|| "FFEB9C".to_string()),
+        );
+        let new_fill = argb(
+            &get_arg_value(args, "--new-fill")
+                .or_else(|| cfg.as_ref().and_then(|c| c.new_fill.clone()))
+                .unwrap_or_else(|| "C6EFCE".to_string()),
+        );
+        let changes_sheet = get_arg_value(args, "--changes-sheet")
+            .or_else(|| cfg.as_ref().and_then(|c| c.changes_sheet.clone()))
+            .unwrap_or_else(|| "_changes".to_string());
 
-    // 1) PKs first (if configured)
-    if pk_first {
-        for pk in &pk_keys {
-            push_unique(&mut columns, &mut seen, pk.clone());
+        // Snapshots: a dated copy per run, or rows appended to a long History sheet
+        let snapshot_mode = get_arg_value(args, "--snapshot")
+            .or_else(|| cfg.as_ref().and_then(|c| c.snapshot.clone()))
+            .unwrap_or_else(|| "none".to_string())
+            .to_lowercase();
+        if !matches!(snapshot_mode.as_str(), "none" | "sheet" | "history") {
+            return Err(format!("Invalid snapshot mode `{}` (expected none|sheet|history)", snapshot_mode).into());
+        }
+        let snapshot_keep = match get_arg_value(args, "--snapshot-keep") {
+            Some(s) => Some(
+                s.parse::<usize>()
+                    .map_err(|e| format!("Invalid --snapshot-keep `{}`: {}", s, e))?,
+            ),
+            None => cfg.as_ref().and_then(|c| c.snapshot_keep),
+        };
+        if snapshot_keep == Some(0) {
+            return Err("snapshot_keep must be at least 1".into());
+        }
+        let snapshot = (snapshot_mode != "none").then(|| SnapshotSpec {
+            history: snapshot_mode == "history",
+            name: get_arg_value(args, "--snapshot-name")
+                .or_else(|| cfg.as_ref().and_then(|c| c.snapshot_name.clone()))
+                .unwrap_or_else(|| "{sheet} {date}".to_string()),
+            history_sheet: get_arg_value(args, "--history-sheet")
+                .or_else(|| cfg.as_ref().and_then(|c| c.history_sheet.clone()))
+                .unwrap_or_else(|| "History".to_string()),
+            keep: snapshot_keep,
+            date: Local::now().date_naive(),
+        });
+        if let Some(spec) = snapshot.as_ref().filter(|s| !s.history) {
+            if !spec.name.contains("{date}") {
+                return Err(format!("snapshot_name `{}` needs a {{date}} placeholder", spec.name).into());
+            }
         }
-    }
 
-    // 2) ordered groups
-    add_exact(&mut columns, &mut seen, &order_exact, &key_allowed);
-    add_regex(&mut columns, &mut seen, &universe, &order_regex);
-    add_substr(&mut columns, &mut seen, &universe, &order_substr);
+        // Dry run: how the summary is printed
+        let report_format = get_arg_value(args, "--report")
+            .unwrap_or_else(|| "text".to_string())
+            .to_lowercase();
+        if !matches!(report_format.as_str(), "text" | "json") {
+            return Err(format!("Invalid report format `{}` (expected text|json)", report_format).into());
+        }
 
-    // 3) remainder
-    match order_rest_mode.as_str() {
+        Ok(JobOptions {
+            sheet,
+            sheet_by,
+            input_path,
+            ndjson_mode,
+            root,
+            flatten,
+            explode,
+            collapse,
+            pk_keys,
+            pk_optional,
+            pk_norms,
+            dup_policy,
+            merge_mode,
+            protected,
+            pk_first,
+            include_active,
+            include_exact,
+            include_regexes,
+            include_substrs,
+            exclude_exact,
+            exclude_regexes,
+            exclude_substrs,
+            order_exact,
+            order_regex,
+            order_substr,
+            order_rest,
+            clear_mode,
+            hyperlink_map,
+            link_texts,
+            link_mode,
+            labels,
+            types,
+            auto_dates,
+            tz_mode,
+            date_format,
+            datetime_format,
+            formats,
+            format_existing,
+            template_row,
+            inherit_row_style,
+            table,
+            freeze_spec,
+            autofilter,
+            auto_width,
+            sync_mode,
+            archive_sheet,
+            status_column,
+            status_removed,
+            strike_removed,
+            audit,
+            audit_position,
+            track_changes,
+            changed_fill,
+            new_fill,
+            changes_sheet,
+            snapshot,
+            report_format,
+        })
+    }
+
+    // Whether include/exclude filters let `key` into the sheet; PKs always pass
+    fn key_allowed(&self, key: &str) -> bool {
+        if self.pk_keys.iter().any(|k| k == key) {
+            return true;
+        }
+        let included = !self.include_active
+            || self.include_exact.contains(key)
+            || self.include_regexes.iter().any(|re| re.is_match(key))
+            || self.include_substrs.iter().any(|sub| key.contains(sub));
+        // exclusion narrows whatever inclusion let through
+        included
+            && !(self.exclude_exact.contains(key)
+                || self.exclude_regexes.iter().any(|re| re.is_match(key))
+                || self.exclude_substrs.iter().any(|sub| key.contains(sub)))
+    }
+}
+
+// Merge one job's input into `book`. Every sheet a job writes is registered in `claimed`.
+fn run_job(
+    opts: &JobOptions,
+    ctx: &JobContext,
+    book: &mut umya::Spreadsheet,
+    mut existing: Option<&mut calamine::Sheets<io::BufReader<fs::File>>>,
+    claimed: &mut HashMap<String, SheetUse>,
+    reports: &mut Vec<Value>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let out_path = ctx.out_path;
+    let sheet_name = match ctx.split {
+        Some(split) => split.name.clone(),
+        None => opts.sheet.clone(),
+    };
+    // a sheet_by job writes only the sheets it generates
+    if opts.sheet_by.is_none() || ctx.split.is_some() {
+        claim_sheet(claimed, &sheet_name, SheetUse::Data)?;
+    }
+    if opts.sync_mode == "archive" && opts.archive_sheet == sheet_name {
+        return Err(format!("Archive sheet `{}` is the target sheet", opts.archive_sheet).into());
+    }
+    if let Some(spec) = &opts.snapshot {
+        if spec.history {
+            // shared by jobs and generated sheets: its rows carry their `_sheet`
+            claim_sheet(claimed, &spec.history_sheet, SheetUse::History)?;
+        } else {
+            if (opts.sheet_by.is_some() || ctx.jobs > 1) && !spec.name.contains("{sheet}") {
+                return Err(format!(
+                    "snapshot_name `{}` needs a {{sheet}} placeholder when several sheets are written",
+                    spec.name
+                )
+                .into());
+            }
+            // generated sheets claim their own snapshots
+            if opts.sheet_by.is_none() || ctx.split.is_some() {
+                claim_sheet(claimed, &spec.dated_sheet(&sheet_name), SheetUse::Snapshot)?;
+            }
+        }
+    }
+    let mut ndjson_mode = opts.ndjson_mode;
+    let mut types = opts.types.clone(); // plus detected dates and audit columns
+
+    let parsed = match ctx.split {
+        // this sheet's share of the input, already parsed
+        Some(split) => split.rows.clone(),
+        None => {
+            // -------- read input (file or stdin) --------
+            let input = match &opts.input_path {
+                Some(path) => fs::read_to_string(path).map_err(|e| format!("Can't read input `{}`: {}", path, e))?,
+                None => {
+                    let mut input = String::new();
+                    io::stdin().read_to_string(&mut input)?;
+                    input
+                }
+            };
+
+            // If in NDJSON mode but the payload clearly looks like a JSON array, switch modes.
+            let looks_like_array = input.trim_start().starts_with('[');
+            if ndjson_mode && looks_like_array {
+                eprintln!("Note: input looks like a JSON array; overriding NDJSON and parsing as array.");
+                ndjson_mode = false;
+            }
+
+            if ndjson_mode {
+                parse_ndjson(&input, opts.root.as_deref(), &opts.explode, opts.flatten.as_ref())?
+            } else {
+                parse_json_array(&input, opts.root.as_deref(), &opts.explode, opts.flatten.as_ref())?
+            }
+        }
+    };
+
+    // -------- split by column value: each sheet gets its own merge and column plan --------
+    if let (Some(col), None) = (&opts.sheet_by, ctx.split) {
+        // the tool's own sheets are never generated names
+        let mut reserved: HashSet<String> = claimed.keys().cloned().collect();
+        reserved.insert(META_SHEET.to_string());
+        if opts.track_changes {
+            reserved.insert(opts.changes_sheet.clone());
+        }
+        if opts.sync_mode == "archive" {
+            reserved.insert(opts.archive_sheet.clone());
+        }
+        for split in split_by_sheet(book, parsed, col, &sheet_name, &reserved) {
+            let sub = JobContext {
+                split: Some(&split),
+                ..*ctx
+            };
+            run_job(opts, &sub, book, existing.as_deref_mut(), claimed, reports)?;
+        }
+        return Ok(());
+    }
+    let (new_sources, mut new_rows): (Vec<String>, Vec<RowMap>) = parsed.into_iter().unzip();
+
+    // -------- existing workbook values --------
+    let (mut existing_headers, existing) = match existing {
+        Some(wb) => match read_existing_xlsx_values(wb, &sheet_name, &opts.labels, &opts.link_texts) {
+            Ok(data) => data,
+            Err(e) => {
+                eprintln!(
+                    "Warning: couldn't read sheet `{}` of `{}` ({}). Recreating sheet content.",
+                    sheet_name, out_path, e
+                );
+                (Vec::new(), Vec::new())
+            }
+        },
+        None => (Vec::new(), Vec::new()),
+    };
+    // sheet row of each existing row, for reports
+    let (existing_row_nums, mut existing_rows): (Vec<u32>, Vec<RowMap>) = existing.into_iter().unzip();
+
+    // -------- collapse indexed columns --------
+    // Applied to both sides so sheets written before a rule was added migrate too.
+    if !opts.collapse.is_empty() {
+        for row in new_rows.iter_mut().chain(existing_rows.iter_mut()) {
+            collapse_row(row, &opts.collapse);
+        }
+        existing_headers.retain(|h| !opts.collapse.iter().any(|c| c.re.is_match(h)));
+    }
+
+    // -------- typed columns (ISO dates -> Excel serials) --------
+    // Only the new rows: existing cells were read back typed already.
+    convert_typed_values(&mut new_rows, &mut types, opts.auto_dates, &opts.tz_mode);
+
+    // -------- merge by PK --------
+    let input_count = new_rows.len();
+    let existing_count = existing_rows.len();
+    let mut present: HashSet<usize> = HashSet::new(); // existing_rows indices seen in the input
+    let mut row_changes: Vec<RowChange> = vec![RowChange::Untouched; existing_rows.len()];
+    let mut changes: Vec<Change> = Vec::new();
+    let mut input_keys: HashSet<String> = HashSet::new();
+    if !opts.pk_keys.is_empty() {
+        // every sheet row per PK, in sheet order
+        let mut index: HashMap<String, Vec<usize>> = HashMap::new();
+        for (i, row) in existing_rows.iter().enumerate() {
+            if let Some(key) = composite_pk(row, &opts.pk_keys, &opts.pk_optional, &opts.pk_norms) {
+                index.entry(key).or_default().push(i);
+            }
+        }
+
+        // Duplicates are reported whatever the policy; `error` refuses to go on
+        let mut dup_reports: Vec<String> = Vec::new();
+        let mut sheet_dups: Vec<&Vec<usize>> = index.values().filter(|rows| rows.len() > 1).collect();
+        sheet_dups.sort();
+        for rows in sheet_dups {
+            let sheet_rows: Vec<String> = rows.iter().map(|&i| existing_row_nums[i].to_string()).collect();
+            dup_reports.push(format!(
+                "PK `{}` on sheet `{}` rows {}",
+                pk_display(&existing_rows[rows[0]], &opts.pk_keys),
+                sheet_name,
+                sheet_rows.join(", ")
+            ));
+        }
+        let mut occurrences: HashMap<String, Vec<usize>> = HashMap::new(); // key -> input positions
+        for (n, row) in new_rows.iter().enumerate() {
+            if let Some(key) = composite_pk(row, &opts.pk_keys, &opts.pk_optional, &opts.pk_norms) {
+                occurrences.entry(key).or_default().push(n);
+            }
+        }
+        let mut input_dups: Vec<&Vec<usize>> = occurrences.values().filter(|ns| ns.len() > 1).collect();
+        input_dups.sort();
+        for ns in input_dups {
+            let sources: Vec<&str> = ns.iter().map(|&n| new_sources[n].as_str()).collect();
+            dup_reports.push(format!(
+                "PK `{}` in input at {}",
+                pk_display(&new_rows[ns[0]], &opts.pk_keys),
+                sources.join(", ")
+            ));
+        }
+        if !dup_reports.is_empty() {
+            if opts.dup_policy == "error" {
+                return Err(format!("Duplicate primary keys: {}", dup_reports.join("; ")).into());
+            }
+            for r in &dup_reports {
+                eprintln!("Warning: duplicate {} ({})", r, opts.dup_policy);
+            }
+        }
+
+        // key -> sheet rows the input's occurrences went to, in input order
+        let mut targets: HashMap<String, Vec<usize>> = HashMap::new();
+        // sheet row -> its state before this run's first merge into it; diffed once
+        // all occurrences are in, so A -> B -> A is no change
+        let mut originals: BTreeMap<usize, RowMap> = BTreeMap::new();
+        for row in new_rows.drain(..) {
+            let Some(key) = composite_pk(&row, &opts.pk_keys, &opts.pk_optional, &opts.pk_norms) else {
+                present.insert(existing_rows.len());
+                existing_rows.push(row);
+                row_changes.push(RowChange::Added);
+                continue;
+            };
+            input_keys.insert(key.clone());
+            let sheet_rows = index.get(&key).map(|v| v.as_slice()).unwrap_or(&[]);
+            let done = targets.entry(key).or_default();
+            let target = match (opts.dup_policy.as_str(), done.len()) {
+                ("first-wins", n) if n > 0 => continue,
+                ("keep-all", n) => sheet_rows.get(n).copied(),
+                (_, n) if n > 0 => Some(done[0]),
+                ("last-wins", _) => sheet_rows.last().copied(),
+                _ => sheet_rows.first().copied(),
+            };
+            let Some(idx) = target else {
+                done.push(existing_rows.len());
+                present.insert(existing_rows.len());
+                existing_rows.push(row);
+                row_changes.push(RowChange::Added);
+                continue;
+            };
+            done.push(idx);
+
+            let before = existing_rows[idx].clone();
+            if row_changes[idx] != RowChange::Added {
+                originals.entry(idx).or_insert_with(|| before.clone());
+            }
+            merge_row(&mut existing_rows[idx], row, &opts.merge_mode, &opts.protected);
+            if !opts.pk_norms.is_empty() {
+                // matched by normalized form: the sheet keeps showing its own PK spelling
+                for k in &opts.pk_keys {
+                    if let Some(v) = before.get(k) {
+                        existing_rows[idx].insert(k.clone(), v.clone());
+                    }
+                }
+            }
+            if opts.audit {
+                // audit columns belong to the sheet, not the input
+                for k in AUDIT_COLUMNS {
+                    match before.get(k) {
+                        Some(v) => existing_rows[idx].insert(k.to_string(), v.clone()),
+                        None => existing_rows[idx].remove(k),
+                    };
+                }
+            }
+            present.insert(idx);
+        }
+
+        for (idx, original) in originals {
+            let diff = diff_rows(&original, &existing_rows[idx]);
+            if diff.is_empty() {
+                row_changes[idx] = RowChange::Unchanged;
+                continue;
+            }
+            let pk = pk_display(&existing_rows[idx], &opts.pk_keys);
+            row_changes[idx] = RowChange::Updated(diff.iter().map(|(k, _, _)| k.clone()).collect());
+            changes.extend(diff.into_iter().map(|(column, old, new)| Change {
+                pk: pk.clone(),
+                column,
+                old,
+                new,
+            }));
+        }
+    } else {
+        row_changes.resize(existing_rows.len() + new_rows.len(), RowChange::Added);
+        existing_rows.append(&mut new_rows);
+    }
+
+    // -------- sync: existing rows whose PK is absent from the input --------
+    let mut archived_rows: Vec<RowMap> = Vec::new();
+    let mut removed_pks: Vec<String> = Vec::new();
+    let mut marked_pks: Vec<String> = Vec::new();
+    if opts.sync_mode != "none" && input_count == 0 {
+        // most likely a failed export upstream; don't wipe the sheet
+        eprintln!("Warning: input has no rows; skipping --sync {}.", opts.sync_mode);
+    } else if opts.sync_mode != "none" {
+        let missing: Vec<bool> = existing_rows
+            .iter()
+            .map(|row| composite_pk(row, &opts.pk_keys, &opts.pk_optional, &opts.pk_norms).is_some_and(|k| !input_keys.contains(&k)))
+            .collect();
+        if opts.sync_mode == "mark" {
+            // rows that came back lose their mark (Null blanks the cell)
+            let has_status = existing_headers.contains(&opts.status_column) || missing.contains(&true);
+            for (row, gone) in existing_rows.iter_mut().zip(&missing) {
+                if *gone {
+                    marked_pks.push(pk_display(row, &opts.pk_keys));
+                    row.insert(opts.status_column.clone(), Value::String(opts.status_removed.clone()));
+                } else if has_status {
+                    row.insert(opts.status_column.clone(), Value::Null);
+                }
+            }
+        } else {
+            row_changes = row_changes
+                .into_iter()
+                .zip(&missing)
+                .filter(|(_, gone)| !**gone)
+                .map(|(c, _)| c)
+                .collect();
+            let mut kept: Vec<RowMap> = Vec::new();
+            for (row, gone) in existing_rows.drain(..).zip(missing) {
+                if gone {
+                    archived_rows.push(row);
+                } else {
+                    kept.push(row);
+                }
+            }
+            existing_rows = kept;
+            removed_pks = archived_rows.iter().map(|r| pk_display(r, &opts.pk_keys)).collect();
+            if opts.sync_mode == "remove" {
+                archived_rows.clear();
+            }
+        }
+    }
+
+    // -------- union of keys --------
+    let mut all_keys: BTreeSet<String> = BTreeSet::new();
+    for r in &existing_rows {
+        for k in r.keys() {
+            all_keys.insert(k.clone());
+        }
+    }
+
+    // clean empty header cells
+    existing_headers.retain(|h| !h.trim().is_empty());
+
+    // inclusion predicate
+    let pk_set: HashSet<&str> = opts.pk_keys.iter().map(|s| s.as_str()).collect();
+    let key_allowed = |key: &str| opts.key_allowed(key);
+
+    // universe: existing headers (filtered) + remaining keys (natural sorted), all filtered by key_allowed
+    let universe_existing: Vec<String> = existing_headers
+        .iter()
+        .filter(|h| !pk_set.contains(h.as_str()) && key_allowed(h))
+        .cloned()
+        .collect();
+
+    let mut remaining_from_all: Vec<String> = all_keys
+        .iter()
+        .filter(|k| !pk_set.contains(k.as_str()) && key_allowed(k))
+        .cloned()
+        .collect();
+    remaining_from_all.retain(|k| !universe_existing.contains(k));
+    remaining_from_all.sort_by(natural_cmp);
+
+    let mut universe: Vec<String> = Vec::new();
+    universe.extend(universe_existing);
+    universe.extend(remaining_from_all);
+    if opts.audit {
+        // placed by audit_position below (or explicitly via --order)
+        universe.retain(|k| !AUDIT_COLUMNS.contains(&k.as_str()));
+    }
+
+    // ---------- build final columns ----------
+    let mut columns: Vec<String> = Vec::new();
+    let mut seen: HashSet<String> = HashSet::new();
+
+    // 1) PKs first (if configured)
+    if opts.pk_first {
+        for pk in &opts.pk_keys {
+            push_unique(&mut columns, &mut seen, pk.clone());
+        }
+    }
+
+    // 2) ordered groups
+    add_exact(&mut columns, &mut seen, &opts.order_exact, &key_allowed);
+    add_regex(&mut columns, &mut seen, &universe, &opts.order_regex);
+    add_substr(&mut columns, &mut seen, &universe, &opts.order_substr);
+
+    // 3) remainder
+    match opts.order_rest.as_str() {
         "none" => {}
         "alpha" => {
             let mut rest: Vec<String> = universe.into_iter().filter(|k| !seen.contains(k)).collect();
@@ -347,310 +1449,2624 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 push_unique(&mut columns, &mut seen, k);
             }
         }
-    }
-
-    // 4) ensure PKs exist even if pk_first=false and not covered above
-    if !pk_first {
-        for pk in &pk_keys {
-            if !seen.contains(pk) {
-                push_unique(&mut columns, &mut seen, pk.clone());
-            }
+    }
+
+    // 4) ensure PKs exist even if pk_first=false and not covered above
+    if !opts.pk_first {
+        for pk in &opts.pk_keys {
+            if !seen.contains(pk) {
+                push_unique(&mut columns, &mut seen, pk.clone());
+            }
+        }
+    }
+
+    // 5) sync marks need their status column, whatever the filters say
+    if opts.sync_mode == "mark" && all_keys.contains(&opts.status_column) {
+        push_unique(&mut columns, &mut seen, opts.status_column.clone());
+    }
+
+    // 6) audit columns, right after the leading PKs or at the end
+    if opts.audit {
+        let mut at = match opts.audit_position.as_str() {
+            "first" => columns.iter().take_while(|c| opts.pk_keys.contains(c)).count(),
+            _ => columns.len(),
+        };
+        for k in AUDIT_COLUMNS {
+            if seen.insert(k.to_string()) {
+                columns.insert(at, k.to_string());
+                at += 1;
+            }
+        }
+    }
+
+    // a label that is another column's key would put one header on two columns,
+    // and both would read back as the labelled key
+    for (key, label) in &opts.labels {
+        if label != key && (columns.contains(label) || existing_headers.contains(label)) {
+            return Err(format!("Label `{}` for `{}` is also the name of a column", label, key).into());
+        }
+    }
+
+    // changes only count for columns that end up in the sheet
+    changes.retain(|c| columns.contains(&c.column));
+    for rc in row_changes.iter_mut() {
+        if let RowChange::Updated(cols) = rc {
+            cols.retain(|k| columns.contains(k));
+            if cols.is_empty() {
+                *rc = RowChange::Unchanged;
+            }
+        }
+    }
+
+    if opts.audit {
+        let now = Value::from(excel_serial(Local::now().naive_local()));
+        for (row, change) in existing_rows.iter_mut().zip(&row_changes) {
+            stamp_audit(row, change, &now);
+        }
+        for k in AUDIT_COLUMNS {
+            types.insert(k.to_string(), ColumnType::DateTime);
+        }
+    }
+
+    if ctx.dry_run {
+        let matched = present.iter().filter(|&&i| i < existing_count).count();
+        let report = DryRunReport {
+            out: out_path,
+            sheet: &sheet_name,
+            rows: &existing_rows,
+            row_changes: &row_changes,
+            pk_keys: &opts.pk_keys,
+            matched,
+            untouched: existing_count - matched - removed_pks.len() - marked_pks.len(),
+            removed: &removed_pks,
+            marked: &marked_pks,
+            columns_added: columns.iter().filter(|c| !existing_headers.contains(c)).cloned().collect(),
+            columns_dropped: existing_headers.iter().filter(|h| !columns.contains(h)).cloned().collect(),
+        };
+        if opts.report_format == "json" {
+            reports.push(report.to_json()); // printed by main, once all jobs ran
+        } else {
+            report.print_text();
+        }
+        return Ok(());
+    }
+
+    let freeze = match &opts.freeze_spec {
+        Some(spec) => Some(resolve_freeze(spec, &columns, &opts.pk_keys)?),
+        None => None,
+    };
+
+    // -------- write/update XLSX while preserving formatting --------
+    // recorded so the value keeps this sheet on later runs
+    let split_value = match (&opts.sheet_by, ctx.split) {
+        (Some(col), Some(split)) => Some((col.clone(), serde_json::to_string(&split.value)?)),
+        _ => None,
+    };
+    let write_opts = WriteOptions {
+        hyperlink_map: &opts.hyperlink_map,
+        link_mode: &opts.link_mode,
+        clear_mode: &opts.clear_mode,
+        labels: &opts.labels,
+        types: &types,
+        date_format: &opts.date_format,
+        datetime_format: &opts.datetime_format,
+        formats: &opts.formats,
+        format_existing: opts.format_existing,
+        inherit_row_style: opts.inherit_row_style,
+        template_row: opts.template_row,
+        table: opts.table.as_ref(),
+        freeze,
+        autofilter: opts.autofilter,
+        auto_width: opts.auto_width,
+        removed_mark: (opts.sync_mode == "mark" && opts.strike_removed)
+            .then_some((opts.status_column.as_str(), opts.status_removed.as_str())),
+        archive: (opts.sync_mode == "archive").then_some((opts.archive_sheet.as_str(), archived_rows.as_slice())),
+        trim_rows: matches!(opts.sync_mode.as_str(), "remove" | "archive"),
+        highlight: opts.track_changes.then_some((opts.changed_fill.as_str(), opts.new_fill.as_str())),
+        row_changes: &row_changes,
+        change_log: opts.track_changes.then_some((opts.changes_sheet.as_str(), changes.as_slice())),
+        snapshot: opts.snapshot.as_ref(),
+        split_value: split_value.as_ref().map(|(col, v)| (col.as_str(), v.as_str())),
+    };
+    write_xlsx_preserve(book, &sheet_name, &columns, &existing_rows, &write_opts)?;
+    Ok(())
+}
+
+// ---------------- helpers: ordering adders ----------------
+
+fn add_exact<F: Fn(&str) -> bool>(
+    columns: &mut Vec<String>,
+    seen: &mut HashSet<String>,
+    names: &[String],
+    key_allowed: &F,
+) {
+    for name in names {
+        if key_allowed(name) {
+            push_unique(columns, seen, name.clone());
+        }
+    }
+}
+
+fn add_regex(
+    columns: &mut Vec<String>,
+    seen: &mut HashSet<String>,
+    universe: &[String],
+    pats: &[Regex],
+) {
+    for re in pats {
+        for k in universe.iter().filter(|k| re.is_match(k)) {
+            push_unique(columns, seen, k.clone());
+        }
+    }
+}
+
+fn add_substr(
+    columns: &mut Vec<String>,
+    seen: &mut HashSet<String>,
+    universe: &[String],
+    subs: &[String],
+) {
+    for sub in subs {
+        for k in universe.iter().filter(|k| k.contains(sub)) {
+            push_unique(columns, seen, k.clone());
+        }
+    }
+}
+
+// ---------------- Parsing ----------------
+
+// Rows come with where they were read from ("line 12", "line 3, record 2") for reports.
+fn parse_json_array(
+    input: &str,
+    root: Option<&str>,
+    explode: &[Explode],
+    flatten: Option<&Flatten>,
+) -> Result<Vec<(String, RowMap)>, Box<dyn std::error::Error>> {
+    let v: Value = serde_json::from_str(input)?;
+    let v = select_root(v, root)?;
+    let lines = array_element_lines(input, root.unwrap_or(""));
+    records_from_value(v, explode, flatten, "", &lines)
+}
+
+fn parse_ndjson(
+    input: &str,
+    root: Option<&str>,
+    explode: &[Explode],
+    flatten: Option<&Flatten>,
+) -> Result<Vec<(String, RowMap)>, Box<dyn std::error::Error>> {
+    let mut rows = Vec::new();
+    for (lineno, line) in input.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let v: Value = serde_json::from_str(line)
+            .map_err(|e| format!("Invalid JSON on line {}: {}", lineno + 1, e))?;
+        if root.is_some() {
+            // each line is an envelope; its root may hold several records
+            let v = select_root(v, root).map_err(|e| format!("Line {}: {}", lineno + 1, e))?;
+            let origin = format!("line {}, ", lineno + 1);
+            rows.extend(
+                records_from_value(v, explode, flatten, &origin, &[])
+                    .map_err(|e| format!("Line {}: {}", lineno + 1, e))?,
+            );
+        } else {
+            let source = format!("line {}", lineno + 1);
+            let exploded = value_to_rows(v, explode, flatten).map_err(|e| format!("Line {}: {}", lineno + 1, e))?;
+            rows.extend(exploded.into_iter().map(|r| (source.clone(), r)));
+        }
+    }
+    Ok(rows)
+}
+
+fn records_from_value(
+    v: Value,
+    explode: &[Explode],
+    flatten: Option<&Flatten>,
+    origin: &str,
+    lines: &[usize], // line of each element, when known
+) -> Result<Vec<(String, RowMap)>, Box<dyn std::error::Error>> {
+    let arr = match v {
+        Value::Array(arr) => arr,
+        v @ Value::Object(_) => vec![v],
+        _ => return Err("Expected a JSON array of objects or a single object".into()),
+    };
+    let mut rows = Vec::new();
+    for (i, v) in arr.into_iter().enumerate() {
+        let source = match lines.get(i) {
+            Some(line) => format!("{}line {}", origin, line),
+            None => format!("{}record {}", origin, i + 1),
+        };
+        let exploded = value_to_rows(v, explode, flatten).map_err(|e| format!("Input {}: {}", source, e))?;
+        rows.extend(exploded.into_iter().map(|r| (source.clone(), r)));
+    }
+    Ok(rows)
+}
+
+// One input record -> one or more rows (several when exploding arrays).
+fn value_to_rows(
+    v: Value,
+    explode: &[Explode],
+    flatten: Option<&Flatten>,
+) -> Result<Vec<RowMap>, Box<dyn std::error::Error>> {
+    let mut records = vec![v];
+    for ex in explode {
+        let mut exploded = Vec::with_capacity(records.len());
+        for r in records {
+            exploded.extend(ex.apply(r)?);
+        }
+        records = exploded;
+    }
+    records.into_iter().map(|r| value_to_rowmap(r, flatten)).collect()
+}
+
+// Descend to the record list; `root` is already a JSON Pointer (see root_to_pointer).
+fn select_root(v: Value, root: Option<&str>) -> Result<Value, Box<dyn std::error::Error>> {
+    match root {
+        None | Some("") => Ok(v),
+        Some(ptr) => {
+            let mut v = v;
+            match v.pointer_mut(ptr) {
+                Some(found) => Ok(found.take()),
+                None => Err(format!("root `{}` not found in input", ptr).into()),
+            }
+        }
+    }
+}
+
+// Line (1-based) on which each element of the array at `pointer` starts; empty
+// when there is no array there. `input` must already have parsed as JSON.
+fn array_element_lines(input: &str, pointer: &str) -> Vec<usize> {
+    let target: Vec<String> = pointer
+        .split('/')
+        .skip(1)
+        .map(|seg| seg.replace("~1", "/").replace("~0", "~"))
+        .collect();
+    let mut scan = LineScan {
+        bytes: input.as_bytes(),
+        pos: 0,
+        line: 1,
+        target,
+        path: Vec::new(),
+        lines: Vec::new(),
+    };
+    scan.value();
+    scan.lines
+}
+
+// Just enough of a JSON reader to know the path and line of every value
+struct LineScan<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    line: usize,
+    target: Vec<String>,
+    path: Vec<String>,
+    lines: Vec<usize>,
+}
+
+impl LineScan<'_> {
+    fn peek(&self) -> u8 {
+        self.bytes.get(self.pos).copied().unwrap_or(0)
+    }
+
+    fn skip_ws(&mut self) {
+        while matches!(self.peek(), b' ' | b'\t' | b'\r' | b'\n') {
+            if self.peek() == b'\n' {
+                self.line += 1;
+            }
+            self.pos += 1;
+        }
+    }
+
+    // at an opening quote; returns the decoded string
+    fn string(&mut self) -> String {
+        let start = self.pos;
+        self.pos += 1;
+        while self.pos < self.bytes.len() && self.bytes[self.pos] != b'"' {
+            self.pos += if self.bytes[self.pos] == b'\\' { 2 } else { 1 };
+        }
+        self.pos += 1;
+        let raw = std::str::from_utf8(&self.bytes[start..self.pos.min(self.bytes.len())]).unwrap_or("\"\"");
+        serde_json::from_str(raw).unwrap_or_default()
+    }
+
+    fn value(&mut self) {
+        self.skip_ws();
+        match self.peek() {
+            b'{' => {
+                self.pos += 1;
+                loop {
+                    self.skip_ws();
+                    if matches!(self.peek(), b'}' | 0) {
+                        self.pos += 1;
+                        break;
+                    }
+                    let key = self.string();
+                    self.skip_ws();
+                    self.pos += 1; // ':'
+                    self.path.push(key);
+                    self.value();
+                    self.path.pop();
+                    self.skip_ws();
+                    if self.peek() == b',' {
+                        self.pos += 1;
+                    }
+                }
+            }
+            b'[' => {
+                let at_target = self.path == self.target;
+                self.pos += 1;
+                let mut i = 0usize;
+                loop {
+                    self.skip_ws();
+                    if matches!(self.peek(), b']' | 0) {
+                        self.pos += 1;
+                        break;
+                    }
+                    if at_target {
+                        self.lines.push(self.line);
+                    }
+                    self.path.push(i.to_string());
+                    self.value();
+                    self.path.pop();
+                    i += 1;
+                    self.skip_ws();
+                    if self.peek() == b',' {
+                        self.pos += 1;
+                    }
+                }
+            }
+            b'"' => {
+                self.string();
+            }
+            _ => {
+                // number, true, false, null
+                while !matches!(self.peek(), b',' | b']' | b'}' | b' ' | b'\t' | b'\r' | b'\n' | 0) {
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+}
+
+// Accept a JSON Pointer (`/issues`, `/data/items`) as-is; a dotted path
+// (`issues`, `data.items`) is converted, escaping `~` and `/` per RFC 6901.
+fn root_to_pointer(root: &str) -> String {
+    if root.is_empty() || root.starts_with('/') {
+        return root.to_string();
+    }
+    root.split('.')
+        .map(|seg| format!("/{}", seg.replace('~', "~0").replace('/', "~1")))
+        .collect()
+}
+
+fn value_to_rowmap(
+    v: Value,
+    flatten: Option<&Flatten>,
+) -> Result<RowMap, Box<dyn std::error::Error>> {
+    match v {
+        Value::Object(obj) => Ok(object_to_rowmap(obj, flatten)),
+        _ => Err("Each record must be a JSON object (already flattened, or use --flatten)".into()),
+    }
+}
+
+fn object_to_rowmap(obj: JsonMap<String, Value>, flatten: Option<&Flatten>) -> RowMap {
+    match flatten {
+        None => obj.into_iter().collect(),
+        Some(opts) => {
+            let mut out = HashMap::new();
+            for (k, v) in obj {
+                flatten_value(k, v, 1, opts, &mut out);
+            }
+            out
+        }
+    }
+}
+
+// ---------------- Splitting into sheets ----------------
+
+// One generated sheet: its name, the `sheet_by` value (None: blank) and its rows
+struct SplitSheet {
+    name: String,
+    value: Option<String>,
+    rows: Vec<(String, RowMap)>,
+}
+
+// Group rows by `col` and name each group's sheet from `template` ("Issues-{value}").
+// A value keeps the sheet recorded for it in META_SHEET while that still fits the
+// template; new values take the plain name, or " (2)", " (3)", ... when it clashes
+// (ignoring case) with another value's sheet, a sheet already in the workbook or
+// one of `reserved`. Blank values are named "(blank)" but never share its sheet.
+fn split_by_sheet(
+    book: &umya::Spreadsheet,
+    rows: Vec<(String, RowMap)>,
+    col: &str,
+    template: &str,
+    reserved: &HashSet<String>,
+) -> Vec<SplitSheet> {
+    let mut groups: BTreeMap<Option<String>, Vec<(String, RowMap)>> = BTreeMap::new();
+    for (source, row) in rows {
+        let value = row.get(col).filter(|v| !is_blank(v)).map(value_to_text);
+        groups.entry(value).or_default().push((source, row));
+    }
+
+    let recorded = read_meta_split(book, col);
+    let mut taken: HashSet<String> = reserved.iter().map(|s| s.to_lowercase()).collect();
+    let mapped: HashSet<String> = recorded.values().map(|s| s.to_lowercase()).collect();
+    taken.extend(
+        book.get_sheet_collection()
+            .iter()
+            .map(|ws| ws.get_name().to_lowercase())
+            .filter(|n| !mapped.contains(n)),
+    );
+    taken.extend(mapped);
+
+    let base_name = |value: &Option<String>| {
+        sanitize_sheet_name(&template.replace("{value}", value.as_deref().unwrap_or("(blank)")))
+    };
+    groups
+        .into_iter()
+        .map(|(value, rows)| {
+            let base = base_name(&value);
+            let kept = recorded
+                .get(&value)
+                .filter(|name| fits_base(name, &base) && !reserved.iter().any(|r| r.eq_ignore_ascii_case(name)));
+            let name = match kept {
+                Some(name) => name.clone(),
+                None => {
+                    let mut n = 1;
+                    while taken.contains(&numbered(&base, n).to_lowercase()) {
+                        n += 1;
+                    }
+                    let name = numbered(&base, n);
+                    taken.insert(name.to_lowercase());
+                    name
+                }
+            };
+            SplitSheet { name, value, rows }
+        })
+        .collect()
+}
+
+// `base`, or `base (n)` cut to fit 31 characters
+fn numbered(base: &str, n: usize) -> String {
+    if n == 1 {
+        return base.to_string();
+    }
+    let suffix = format!(" ({})", n);
+    let keep = 31 - suffix.chars().count();
+    format!("{}{}", base.chars().take(keep).collect::<String>(), suffix)
+}
+
+// Whether `name` is `base` or one of its numbered variants
+fn fits_base(name: &str, base: &str) -> bool {
+    if name == base {
+        return true;
+    }
+    name.rsplit_once(" (")
+        .and_then(|(_, n)| n.strip_suffix(')')?.parse::<usize>().ok())
+        .is_some_and(|n| n > 1 && numbered(base, n) == name)
+}
+
+// Excel sheet names: at most 31 characters, none of []:*?/\, no leading/trailing '
+fn sanitize_sheet_name(name: &str) -> String {
+    let cleaned: String = name
+        .chars()
+        .map(|c| if matches!(c, '[' | ']' | ':' | '*' | '?' | '/' | '\\') { '_' } else { c })
+        .collect();
+    let cut: String = cleaned.trim_matches('\'').chars().take(31).collect();
+    let cut = cut.trim_end_matches('\'').to_string();
+    if cut.trim().is_empty() {
+        "_".to_string()
+    } else {
+        cut
+    }
+}
+
+// ---------------- Exploding arrays ----------------
+
+struct Explode {
+    name: String,         // path as given (dotted or JSON Pointer)
+    pointer: String,      // JSON Pointer form of `name`
+    key: Option<String>,  // configured child key, as given
+    pk_column: String,    // `<name>#` (element index) or `<name>.<key>`
+}
+
+impl Explode {
+    fn new(name: &str, key: Option<String>, sep: &str) -> Self {
+        // column names as flattening produces them, whichever way the path was written
+        let flat = |path: &str| {
+            path.split(['.', '/'])
+                .filter(|p| !p.is_empty())
+                .collect::<Vec<_>>()
+                .join(sep)
+        };
+        let label = flat(name);
+        let pk_column = match &key {
+            Some(k) => format!("{}{}{}", label, sep, flat(k)),
+            None => format!("{}#", label),
+        };
+        Explode {
+            name: name.to_string(),
+            pointer: root_to_pointer(name),
+            key,
+            pk_column,
+        }
+    }
+
+    // Replace the array at `name` by each of its elements in turn, copying the
+    // parent fields. An empty or missing array leaves a single row without it.
+    // Every element must carry the configured child key; it is copied to the
+    // top-level `pk_column`, so the PK exists without --flatten (or past its depth).
+    fn apply(&self, mut record: Value) -> Result<Vec<Value>, String> {
+        // a pre-flattened record may carry the path as a literal top-level key
+        let pointer = match &record {
+            Value::Object(obj) if obj.contains_key(&self.name) => {
+                format!("/{}", self.name.replace('~', "~0").replace('/', "~1"))
+            }
+            _ => self.pointer.clone(),
+        };
+        let items = match record.pointer_mut(&pointer) {
+            Some(slot) if slot.is_array() => slot.take(),
+            _ => return Ok(vec![record]),
+        };
+        let items = match items {
+            Value::Array(items) if !items.is_empty() => items,
+            _ => {
+                remove_at_pointer(&mut record, &pointer);
+                return Ok(vec![record]);
+            }
+        };
+        let mut out = Vec::with_capacity(items.len());
+        for (i, item) in items.into_iter().enumerate() {
+            let element_id = match &self.key {
+                Some(key) => match item.pointer(&root_to_pointer(key)) {
+                    Some(v) if !v.is_null() => v.clone(),
+                    _ => return Err(format!("element {} of `{}` has no `{}` (explode key)", i, self.name, key)),
+                },
+                None => Value::from(i),
+            };
+            let mut r = record.clone();
+            if let Some(slot) = r.pointer_mut(&pointer) {
+                *slot = item;
+            }
+            if let Value::Object(obj) = &mut r {
+                obj.insert(self.pk_column.clone(), element_id);
+            }
+            out.push(r);
+        }
+        Ok(out)
+    }
+}
+
+fn remove_at_pointer(v: &mut Value, pointer: &str) {
+    let Some((parent, last)) = pointer.rsplit_once('/') else {
+        return;
+    };
+    let last = last.replace("~1", "/").replace("~0", "~");
+    match v.pointer_mut(parent) {
+        Some(Value::Object(obj)) => {
+            obj.remove(&last);
+        }
+        Some(Value::Array(arr)) => {
+            if let Ok(i) = last.parse::<usize>() {
+                if i < arr.len() {
+                    arr.remove(i);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+// ---------------- Flattening ----------------
+
+struct Flatten {
+    sep: String,
+    max_depth: Option<usize>,
+}
+
+// Walk nested objects/arrays producing keys like `fields.components.0.name`
+// (same shape as jq `paths(scalars)`). Empty objects/arrays yield no key;
+// containers at `max_depth` segments are kept whole (written as JSON text).
+fn flatten_value(key: String, v: Value, depth: usize, opts: &Flatten, out: &mut RowMap) {
+    let at_limit = opts.max_depth.is_some_and(|max| depth >= max);
+    match v {
+        Value::Object(obj) if !at_limit => {
+            for (k, child) in obj {
+                let child_key = format!("{}{}{}", key, opts.sep, k);
+                flatten_value(child_key, child, depth + 1, opts, out);
+            }
+        }
+        Value::Array(arr) if !at_limit => {
+            for (i, child) in arr.into_iter().enumerate() {
+                let child_key = format!("{}{}{}", key, opts.sep, i);
+                flatten_value(child_key, child, depth + 1, opts, out);
+            }
+        }
+        other => {
+            out.insert(key, other);
+        }
+    }
+}
+
+// ---------------- Collapsing indexed columns ----------------
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum CollapseRule {
+    Column(String),
+    Full { column: String, sep: Option<String> },
+}
+
+struct Collapse {
+    re: Regex,
+    column: String,
+    sep: String,
+}
+
+// Replace all keys matching a rule (fields.labels.0, .1, … .17) by one joined
+// column, in natural (index) order. Null/empty values are skipped.
+fn collapse_row(row: &mut RowMap, rules: &[Collapse]) {
+    for rule in rules {
+        let mut keys: Vec<String> = row.keys().filter(|k| rule.re.is_match(k)).cloned().collect();
+        if keys.is_empty() {
+            continue;
+        }
+        keys.sort_by(natural_cmp);
+        let parts: Vec<String> = keys
+            .iter()
+            .filter_map(|k| row.remove(k))
+            .map(|v| value_to_text(&v))
+            .filter(|t| !t.is_empty())
+            .collect();
+        let joined = if parts.is_empty() {
+            Value::Null
+        } else {
+            Value::String(parts.join(&rule.sep))
+        };
+        row.insert(rule.column.clone(), joined);
+    }
+}
+
+// ---------------- Hyperlink templates ----------------
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum HyperlinkRule {
+    Url(String),
+    Full {
+        url: String,
+        text: Option<String>,
+        encode: Option<bool>,
+    },
+}
+
+// `{value}` is the cell's own value, `{other.column}` any column of the same
+// row; placeholders are URL-encoded unless written `{name|raw}` or encode=false.
+struct LinkTemplate {
+    url: String,
+    text: Option<String>,
+    encode: bool,
+}
+
+impl LinkTemplate {
+    fn new(url: &str, text: Option<String>, encode: bool) -> Self {
+        // a plain base (no placeholders) keeps the old `BASE + value` meaning
+        let url = if placeholder_re().is_match(url) {
+            url.to_string()
+        } else {
+            format!("{}{{value}}", url)
+        };
+        LinkTemplate { url, text, encode }
+    }
+
+    fn from_rule(rule: &HyperlinkRule) -> Self {
+        match rule {
+            HyperlinkRule::Url(url) => LinkTemplate::new(url, None, true),
+            HyperlinkRule::Full { url, text, encode } => {
+                LinkTemplate::new(url, text.clone(), encode.unwrap_or(true))
+            }
+        }
+    }
+
+    // (url, display text if templated); None when any URL placeholder is empty
+    fn render(&self, key: &str, row: &RowMap) -> Option<(String, Option<String>)> {
+        let url = fill_placeholders(&self.url, key, row, self.encode, true)?;
+        let text = self
+            .text
+            .as_ref()
+            .and_then(|t| fill_placeholders(t, key, row, false, false));
+        Some((url, text))
+    }
+}
+
+// Regex matching what `text` renders to, capturing the column's own value at
+// each `{value}`; None when the text doesn't show the value at all
+fn link_text_re(text: &str, key: &str) -> Option<Regex> {
+    let mut pattern = String::from("(?s)^");
+    let mut own = false;
+    let mut last = 0;
+    for caps in placeholder_re().captures_iter(text) {
+        let m = caps.get(0).expect("whole match");
+        pattern.push_str(&regex::escape(&text[last..m.start()]));
+        let name = caps[1].trim();
+        // ids rarely contain what the other columns (summaries, names) do
+        if name == "value" || name == key {
+            pattern.push_str("(.*?)");
+            own = true;
+        } else {
+            pattern.push_str(".*");
+        }
+        last = m.end();
+    }
+    pattern.push_str(&regex::escape(&text[last..]));
+    pattern.push('$');
+    if !own {
+        return None;
+    }
+    Regex::new(&pattern).ok()
+}
+
+// The value behind a rendered link text (every `{value}` must agree)
+fn link_text_value(re: &Regex, shown: &str) -> Option<String> {
+    let caps = re.captures(shown)?;
+    let first = caps.get(1)?.as_str();
+    caps.iter()
+        .skip(1)
+        .all(|c| c.is_some_and(|c| c.as_str() == first))
+        .then(|| first.to_string())
+}
+
+fn placeholder_re() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(r"\{([^{}|]+)(\|raw)?\}").expect("static regex"))
+}
+
+// Substitute `{name}` placeholders from the row. With `required`, an empty
+// placeholder yields None (no link rather than a broken URL).
+fn fill_placeholders(
+    tpl: &str,
+    key: &str,
+    row: &RowMap,
+    encode: bool,
+    required: bool,
+) -> Option<String> {
+    let mut out = String::with_capacity(tpl.len());
+    let mut last = 0;
+    for caps in placeholder_re().captures_iter(tpl) {
+        let m = caps.get(0).expect("whole match");
+        let name = caps[1].trim();
+        let col = if name == "value" { key } else { name };
+        let text = row.get(col).map(value_to_text).unwrap_or_default();
+        if text.is_empty() && required {
+            return None;
+        }
+        out.push_str(&tpl[last..m.start()]);
+        if encode && caps.get(2).is_none() {
+            out.push_str(&url_encode(&text));
+        } else {
+            out.push_str(&text);
+        }
+        last = m.end();
+    }
+    out.push_str(&tpl[last..]);
+    Some(out)
+}
+
+// Percent-encode everything but RFC 3986 unreserved characters
+fn url_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => out.push(b as char),
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+// ---------------- Column types / dates ----------------
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ColumnType {
+    DateTime,
+    Date,
+    Number,
+    Text,
+}
+
+fn parse_column_type(s: &str) -> Result<ColumnType, Box<dyn std::error::Error>> {
+    match s.to_lowercase().as_str() {
+        "datetime" => Ok(ColumnType::DateTime),
+        "date" => Ok(ColumnType::Date),
+        "number" => Ok(ColumnType::Number),
+        "text" | "string" => Ok(ColumnType::Text),
+        other => Err(format!("Invalid column type `{}` (expected datetime|date|number|text)", other).into()),
+    }
+}
+
+// Convert string values of typed columns in place. With `auto_dates`, ISO-8601
+// strings in untyped columns are converted too and their column is recorded in
+// `types` (datetime wins over date when a column mixes both).
+fn convert_typed_values(
+    rows: &mut [RowMap],
+    types: &mut HashMap<String, ColumnType>,
+    auto_dates: bool,
+    tz_mode: &str,
+) {
+    let mut detected: HashMap<String, ColumnType> = HashMap::new();
+    for row in rows.iter_mut() {
+        for (key, v) in row.iter_mut() {
+            let Value::String(s) = v else {
+                continue;
+            };
+            match types.get(key) {
+                Some(ColumnType::DateTime) => {
+                    if let Some((dt, _)) = parse_iso_datetime(s, tz_mode) {
+                        *v = float_to_value(excel_serial(dt));
+                    }
+                }
+                Some(ColumnType::Date) => {
+                    if let Some((dt, _)) = parse_iso_datetime(s, tz_mode) {
+                        *v = float_to_value(excel_serial(dt).floor());
+                    }
+                }
+                Some(ColumnType::Number) => {
+                    if let Ok(f) = s.trim().parse::<f64>() {
+                        if f.is_finite() {
+                            *v = float_to_value(f);
+                        }
+                    }
+                }
+                Some(ColumnType::Text) => {}
+                None if auto_dates => {
+                    if let Some((dt, has_time)) = parse_iso_datetime(s, tz_mode) {
+                        *v = float_to_value(excel_serial(dt));
+                        let ty = if has_time { ColumnType::DateTime } else { ColumnType::Date };
+                        let slot = detected.entry(key.clone()).or_insert(ty);
+                        if ty == ColumnType::DateTime {
+                            *slot = ColumnType::DateTime;
+                        }
+                    }
+                }
+                None => {}
+            }
+        }
+    }
+    types.extend(detected);
+}
+
+// RFC 3339 / ISO-8601 (incl. Jira's `2024-05-01T10:22:33.000+0200`) -> wall-clock
+// time per `tz_mode` (utc|local|drop), plus whether a time part was present.
+fn parse_iso_datetime(s: &str, tz_mode: &str) -> Option<(NaiveDateTime, bool)> {
+    let s = s.trim();
+    let b = s.as_bytes();
+    if b.len() < 10 || b[4] != b'-' || b[7] != b'-' || !b[..4].iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    if b.len() == 10 {
+        let d = NaiveDate::parse_from_str(s, "%Y-%m-%d").ok()?;
+        return Some((d.and_hms_opt(0, 0, 0)?, false));
+    }
+    let with_offset: Option<DateTime<FixedOffset>> = DateTime::parse_from_rfc3339(s)
+        .or_else(|_| DateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S%.f%z"))
+        .or_else(|_| DateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S%.f%z"))
+        .ok();
+    if let Some(dt) = with_offset {
+        let naive = match tz_mode {
+            "utc" => dt.naive_utc(),
+            "local" => dt.with_timezone(&Local).naive_local(),
+            _ /* drop */ => dt.naive_local(),
+        };
+        return Some((naive, true));
+    }
+    ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M"]
+        .iter()
+        .find_map(|f| NaiveDateTime::parse_from_str(s, f).ok())
+        .map(|dt| (dt, true))
+}
+
+// Days since 1899-12-30 (Excel's 1900 date system), fraction = time of day
+fn excel_serial(dt: NaiveDateTime) -> f64 {
+    let epoch = NaiveDate::from_ymd_opt(1899, 12, 30)
+        .and_then(|d| d.and_hms_opt(0, 0, 0))
+        .expect("valid epoch");
+    (dt - epoch).num_milliseconds() as f64 / 86_400_000.0
+}
+
+// Give a cell a number format unless it already has a non-General one
+fn ensure_number_format(cell: &mut umya::Cell, code: &str) {
+    let has_format = cell
+        .get_style()
+        .get_number_format()
+        .is_some_and(|f| *f.get_number_format_id() != 0 && f.get_format_code() != "General");
+    if !has_format {
+        cell.get_style_mut().get_number_format_mut().set_format_code(code);
+    }
+}
+
+// ---------------- Column formats ----------------
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct ColumnFormat {
+    number_format: Option<String>, // e.g. "0.00", "yyyy-mm-dd", "0%"
+    align: Option<String>,         // left|center|right|general|justify|fill|...
+    valign: Option<String>,        // top|center|bottom|justify|distributed
+    wrap: Option<bool>,
+    font: Option<String>,
+    font_size: Option<f64>,
+    bold: Option<bool>,
+    italic: Option<bool>,
+    color: Option<String>, // font colour, RRGGBB or AARRGGBB
+}
+
+impl ColumnFormat {
+    fn validate(&self) -> Result<(), String> {
+        if let Some(a) = &self.align {
+            a.parse::<umya::HorizontalAlignmentValues>()
+                .map_err(|_| format!("invalid align `{}`", a))?;
+        }
+        if let Some(a) = &self.valign {
+            a.parse::<umya::VerticalAlignmentValues>()
+                .map_err(|_| format!("invalid valign `{}`", a))?;
+        }
+        Ok(())
+    }
+
+    fn apply(&self, cell: &mut umya::Cell) {
+        let style = cell.get_style_mut();
+        if let Some(code) = &self.number_format {
+            style.get_number_format_mut().set_format_code(code);
+        }
+        if let Some(h) = self.align.as_ref().and_then(|a| a.parse().ok()) {
+            style.get_alignment_mut().set_horizontal(h);
+        }
+        if let Some(v) = self.valign.as_ref().and_then(|a| a.parse().ok()) {
+            style.get_alignment_mut().set_vertical(v);
+        }
+        if let Some(w) = self.wrap {
+            style.get_alignment_mut().set_wrap_text(w);
+        }
+        let font = style.get_font_mut();
+        if let Some(name) = &self.font {
+            font.set_name(name);
+        }
+        if let Some(size) = self.font_size {
+            font.set_size(size);
+        }
+        if let Some(b) = self.bold {
+            font.set_bold(b);
+        }
+        if let Some(i) = self.italic {
+            font.set_italic(i);
+        }
+        if let Some(c) = &self.color {
+            font.get_color_mut().set_argb(argb(c));
+        }
+    }
+}
+
+// "FF0000" / "#FF0000" -> "FFFF0000" (opaque); 8-digit values pass through
+fn argb(color: &str) -> String {
+    let c = color.trim_start_matches('#').to_uppercase();
+    if c.len() == 6 {
+        format!("FF{}", c)
+    } else {
+        c
+    }
+}
+
+// ---------------- Read existing values (calamine) ----------------
+
+// Existing rows with their (1-based) sheet row
+type SheetRows = Vec<(u32, RowMap)>;
+
+fn read_existing_xlsx_values(
+    wb: &mut calamine::Sheets<io::BufReader<fs::File>>,
+    sheet_name: &str,
+    labels: &HashMap<String, String>,
+    link_texts: &HashMap<String, String>,
+) -> Result<(Vec<String>, SheetRows), Box<dyn std::error::Error>> {
+    // Display labels -> keys and link text templates: the current config,
+    // overridden by what we recorded when the sheet was written
+    let mut label_to_key: HashMap<String, String> =
+        labels.iter().map(|(k, l)| (l.clone(), k.clone())).collect();
+    let mut link_texts = link_texts.clone();
+    if let Some(Ok(meta)) = wb.worksheet_range(META_SHEET) {
+        for r in meta.rows().skip(1) {
+            let cell = |i: usize| r.get(i).map(cell_to_string).unwrap_or_default();
+            if cell(0) != sheet_name || cell(1).is_empty() {
+                continue;
+            }
+            if !cell(2).is_empty() {
+                label_to_key.insert(cell(2), cell(1));
+            }
+            if !cell(3).is_empty() {
+                link_texts.insert(cell(1), cell(3));
+            }
+        }
+    }
+
+    let maybe_range = wb.worksheet_range(sheet_name);
+
+    let range = match maybe_range {
+        Some(Ok(r)) => r,
+        Some(Err(e)) => return Err(Box::<dyn std::error::Error>::from(e)),
+        None => return Ok((Vec::new(), Vec::new())),
+    };
+
+    let mut rows_iter = range.rows();
+    let header_cells = match rows_iter.next() {
+        Some(r) => r.to_vec(),
+        None => return Ok((Vec::new(), Vec::new())),
+    };
+
+    let headers: Vec<String> = header_cells
+        .iter()
+        .map(cell_to_string)
+        .map(|h| label_to_key.get(&h).cloned().unwrap_or(h))
+        .collect();
+
+    let mut rows: Vec<RowMap> = Vec::new();
+    for r in rows_iter {
+        let mut map = HashMap::new();
+        for (i, cell) in r.iter().enumerate() {
+            if let Some(col) = headers.get(i) {
+                if col.trim().is_empty() {
+                    continue;
+                }
+                map.insert(col.clone(), cell_to_value(cell));
+            }
+        }
+        rows.push(map);
+    }
+
+    // HYPERLINK() formulas written without a cached result read back empty;
+    // recover their display text from the formula itself.
+    let (start_row, start_col) = range.start().unwrap_or((0, 0));
+    if let Some(Ok(formulas)) = wb.worksheet_formula(sheet_name) {
+        let (f_row, f_col) = formulas.start().unwrap_or((0, 0));
+        for (r, c, f) in formulas.used_cells() {
+            let (abs_row, abs_col) = (f_row + r as u32, f_col + c as u32);
+            let Some(text) = hyperlink_formula_text(f) else {
+                continue;
+            };
+            if abs_row <= start_row || abs_col < start_col {
+                continue;
+            }
+            let Some(col) = headers.get((abs_col - start_col) as usize) else {
+                continue;
+            };
+            if col.trim().is_empty() {
+                continue;
+            }
+            let idx = (abs_row - start_row - 1) as usize;
+            if rows.len() <= idx {
+                rows.resize_with(idx + 1, HashMap::new);
+            }
+            let slot = rows[idx].entry(col.clone()).or_insert(Value::Null);
+            if slot.is_null() {
+                *slot = Value::String(text);
+            }
+        }
+    }
+    // Cells showing a link text ("Open ABC-1") hold the value inside it
+    for (key, text) in &link_texts {
+        let Some(re) = link_text_re(text, key) else {
+            continue;
+        };
+        for row in rows.iter_mut() {
+            let Some(Value::String(shown)) = row.get(key) else {
+                continue;
+            };
+            if let Some(value) = link_text_value(&re, shown) {
+                row.insert(key.clone(), Value::String(value));
+            }
+        }
+    }
+
+    // pair each row with its sheet row (the range may not start at A1), then drop empty ones
+    let rows: SheetRows = rows
+        .into_iter()
+        .enumerate()
+        .map(|(i, map)| (start_row + i as u32 + 2, map))
+        .filter(|(_, map)| map.values().any(|v| !v.is_null()))
+        .collect();
+
+    Ok((headers, rows))
+}
+
+// `HYPERLINK("url","text")` -> text (calamine gives formulas without `=`)
+fn hyperlink_formula_text(formula: &str) -> Option<String> {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    let re = RE.get_or_init(|| {
+        Regex::new(r#"(?i)^=?\s*HYPERLINK\(\s*"(?:[^"]|"")*"\s*[,;]\s*"((?:[^"]|"")*)"\s*\)\s*$"#)
+            .expect("static regex")
+    });
+    let caps = re.captures(formula)?;
+    let text = caps[1].replace("\"\"", "\"");
+    if text.is_empty() {
+        None
+    } else {
+        Some(text)
+    }
+}
+
+// Typed readback so untouched rows are rewritten with the same cell types.
+// Dates come back as their serial number; the cell keeps its date format.
+fn cell_to_value(cell: &DataType) -> Value {
+    match cell {
+        DataType::Empty => Value::Null,
+        DataType::String(s) if s.is_empty() => Value::Null,
+        DataType::String(s) => Value::String(s.clone()),
+        DataType::Int(i) => Value::from(*i),
+        DataType::Float(f) | DataType::DateTime(f) | DataType::Duration(f) => float_to_value(*f),
+        DataType::Bool(b) => Value::Bool(*b),
+        DataType::DateTimeIso(s) | DataType::DurationIso(s) => Value::String(s.clone()),
+        DataType::Error(e) => Value::String(format!("ERR:{:?}", e)),
+    }
+}
+
+// Whole floats become integers so `42.0` read back still matches a PK of `42`.
+fn float_to_value(f: f64) -> Value {
+    if f.fract() == 0.0 && f.abs() < 9.007_199_254_740_992e15 {
+        Value::from(f as i64)
+    } else {
+        serde_json::Number::from_f64(f)
+            .map(Value::Number)
+            .unwrap_or(Value::Null)
+    }
+}
+
+fn cell_to_string(cell: &DataType) -> String {
+    match cell {
+        DataType::Empty => String::new(),
+        DataType::String(s) => s.to_string(),
+        DataType::Float(f) => {
+            if f.fract() == 0.0 {
+                format!("{}", *f as i64)
+            } else {
+                f.to_string()
+            }
+        }
+        DataType::Int(i) => i.to_string(),
+        DataType::Bool(b) => b.to_string(),
+        DataType::Error(e) => format!("ERR:{:?}", e),
+        DataType::DateTime(v) => v.to_string(),
+        DataType::Duration(v) => v.to_string(),
+        DataType::DateTimeIso(s) => s.clone(),
+        DataType::DurationIso(s) => s.clone(),
+    }
+}
+
+// ---------------- PK handling ----------------
+
+// Fold an input row into the matched sheet row. `replace` takes the input row
+// as is, `patch` only overwrites keys the input has, `patch-non-null` also
+// skips null input values. Protected columns keep a non-empty sheet value.
+fn merge_row(old: &mut RowMap, new: RowMap, mode: &str, protected: &HashSet<String>) {
+    let mut merged = std::mem::take(old);
+    if mode == "replace" {
+        merged.retain(|k, _| protected.contains(k));
+    }
+    for (k, v) in new {
+        if protected.contains(&k) && merged.get(&k).is_some_and(|o| !is_blank(o)) {
+            continue;
+        }
+        if mode == "patch-non-null" && v.is_null() {
+            continue;
+        }
+        merged.insert(k, v);
+    }
+    *old = merged;
+}
+
+// `optional` columns (exploded element keys) may be empty without voiding the key.
+fn composite_pk(
+    row: &HashMap<String, Value>,
+    pk_cols: &[String],
+    optional: &[String],
+    norms: &HashMap<String, PkNorm>,
+) -> Option<String> {
+    let mut parts: Vec<String> = Vec::with_capacity(pk_cols.len());
+    for c in pk_cols {
+        let part = match row.get(c) {
+            Some(Value::String(s)) => s.clone(),
+            Some(Value::Number(n)) => n.to_string(),
+            Some(Value::Bool(b)) => b.to_string(),
+            Some(Value::Null) | None if optional.contains(c) => String::new(),
+            Some(Value::Null) | None => return None,
+            Some(other) => other.to_string(),
+        };
+        match norms.get(c).or_else(|| norms.get("*")) {
+            Some(norm) => parts.push(norm.apply(part)),
+            None => parts.push(part),
+        }
+    }
+    Some(parts.join("\u{1F}"))
+}
+
+// How a PK value is normalized for matching only; the sheet shows the original
+#[derive(Clone, Copy, Default)]
+struct PkNorm {
+    nfc: bool,      // Unicode NFC (composed é == e + combining accent)
+    trim: bool,     // surrounding whitespace
+    casefold: bool, // ABC-1 == abc-1
+    numeric: bool,  // 42 == "42" == 42.0 == "042"
+}
+
+impl PkNorm {
+    fn parse(spec: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut norm = PkNorm::default();
+        for opt in spec.split(['+', '|']).map(|o| o.trim().to_lowercase()) {
+            match opt.as_str() {
+                "nfc" => norm.nfc = true,
+                "trim" => norm.trim = true,
+                "casefold" | "case" | "lower" => norm.casefold = true,
+                "numeric" | "number" => norm.numeric = true,
+                "all" => {
+                    norm = PkNorm { nfc: true, trim: true, casefold: true, numeric: true };
+                }
+                "" | "none" => {}
+                other => {
+                    return Err(format!(
+                        "Invalid PK normalization `{}` (expected trim|casefold|numeric|nfc|all)",
+                        other
+                    )
+                    .into())
+                }
+            }
+        }
+        Ok(norm)
+    }
+
+    fn apply(&self, s: String) -> String {
+        let mut s = if self.nfc { s.nfc().collect() } else { s };
+        if self.trim {
+            s = s.trim().to_string();
+        }
+        if self.casefold {
+            s = s.to_lowercase();
+        }
+        if self.numeric {
+            if let Ok(f) = s.trim().parse::<f64>() {
+                if f.is_finite() {
+                    s = value_to_text(&float_to_value(f));
+                }
+            }
+        }
+        s
+    }
+}
+
+// ---------------- Write XLSX while preserving formatting ----------------
+
+// How cells are presented; the data itself is `columns` x `rows`.
+#[derive(Clone, Copy)]
+struct WriteOptions<'a> {
+    hyperlink_map: &'a HashMap<String, LinkTemplate>,
+    link_mode: &'a str,
+    clear_mode: &'a str,
+    labels: &'a HashMap<String, String>,
+    types: &'a HashMap<String, ColumnType>,
+    date_format: &'a str,
+    datetime_format: &'a str,
+    formats: &'a HashMap<String, ColumnFormat>,
+    format_existing: bool,
+    inherit_row_style: bool,
+    template_row: Option<u32>,
+    table: Option<&'a TableSpec>,
+    freeze: Option<(u32, u32)>, // top-left cell of the scrolling pane
+    autofilter: Option<bool>, // Some(false): remove the sheet filter
+    auto_width: Option<(f64, f64)>, // (min, max) column width
+    removed_mark: Option<(&'a str, &'a str)>, // strike rows with (status column, value)
+    archive: Option<(&'a str, &'a [RowMap])>, // sheet + rows moved out by --sync archive
+    trim_rows: bool,                          // blank rows below the data (rows were dropped)
+    highlight: Option<(&'a str, &'a str)>,    // (changed, new) fill colours, ARGB
+    row_changes: &'a [RowChange],             // per row in `rows`
+    change_log: Option<(&'a str, &'a [Change])>,
+    snapshot: Option<&'a SnapshotSpec>,
+    split_value: Option<(&'a str, &'a str)>, // sheet_by: (column, JSON of this sheet's value)
+}
+
+fn write_xlsx_preserve(
+    book: &mut umya::Spreadsheet,
+    sheet_name: &str,
+    columns: &[String],
+    rows: &[HashMap<String, Value>],
+    opts: &WriteOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let WriteOptions {
+        hyperlink_map,
+        link_mode,
+        clear_mode,
+        labels,
+        types,
+        date_format,
+        datetime_format,
+        formats,
+        format_existing,
+        inherit_row_style,
+        template_row,
+        table,
+        freeze,
+        autofilter,
+        auto_width,
+        removed_mark,
+        archive,
+        trim_rows,
+        highlight,
+        row_changes,
+        change_log,
+        snapshot,
+        split_value,
+    } = *opts;
+
+    // Ensure sheet exists (create or rename default)
+    if book.get_sheet_by_name(sheet_name).is_none() {
+        if sheet_name != "Sheet1" {
+            if let Some(ws1) = book.get_sheet_by_name_mut("Sheet1") {
+                ws1.set_name(sheet_name);
+            } else {
+                let _ = book.new_sheet(sheet_name);
+            }
+        } else {
+            let _ = book.new_sheet(sheet_name);
+        }
+    }
+
+    let recorded_widths = read_meta_widths(book, sheet_name);
+    // Widths belong to positions, which shift when a column is inserted or dropped:
+    // a position's recorded width is the one of the key its header still names
+    let recorded_by_col: HashMap<u32, f64> = {
+        let mut label_keys: HashMap<String, String> =
+            labels.iter().map(|(k, l)| (l.clone(), k.clone())).collect();
+        label_keys.extend(read_meta_labels(book, sheet_name));
+        let ws = book.get_sheet_by_name(sheet_name).expect("sheet must exist");
+        (1..=ws.get_highest_column())
+            .filter_map(|c| {
+                let header = ws.get_value((c, 1));
+                let key = label_keys.get(&header).unwrap_or(&header);
+                Some((c, *recorded_widths.get(key)?))
+            })
+            .collect()
+    };
+
+    // Now we can safely get it mutably
+    let ws = book
+        .get_sheet_by_name_mut(sheet_name)
+        .expect("sheet must exist");
+
+    // Rows past the previous end of the sheet are appended by this run
+    let prev_last_row = ws.get_highest_row().max(1);
+    let last_row = rows.len() as u32 + 1;
+    if inherit_row_style && last_row > prev_last_row {
+        let source = template_row.unwrap_or(prev_last_row);
+        if source >= 2 || template_row.is_some() {
+            inherit_row_style_from(ws, source, prev_last_row + 1, last_row, columns.len() as u32);
+        }
+    }
+
+    // Header row (keeps existing styles); labels replace the raw key
+    for (c_idx, col_name) in columns.iter().enumerate() {
+        let col = (c_idx as u32) + 1;
+        let header = labels.get(col_name).unwrap_or(col_name);
+        ws.get_cell_mut((col, 1)).set_value_string(header);
+    }
+
+    // Data rows (starting at row 2) — preserves formatting of those cells
+    let native_links = link_mode == "native";
+    for (r_idx, rowmap) in rows.iter().enumerate() {
+        let row_num = (r_idx as u32) + 2;
+        let struck = removed_mark
+            .is_some_and(|(col, val)| rowmap.get(col).and_then(|v| v.as_str()) == Some(val));
+        for (c_idx, key) in columns.iter().enumerate() {
+            let col = (c_idx as u32) + 1;
+
+            // Changed cells / new rows get a fill; last run's highlights are cleared
+            if let Some((changed_fill, new_fill)) = highlight {
+                let want = match row_changes.get(r_idx) {
+                    Some(RowChange::Added) => Some(new_fill),
+                    Some(RowChange::Updated(cols)) if cols.contains(key) => Some(changed_fill),
+                    _ => None,
+                };
+                let current = ws
+                    .get_cell((col, row_num))
+                    .and_then(|c| c.get_style().get_background_color())
+                    .map(|c| c.get_argb().to_string());
+                match want {
+                    Some(fill) if current.as_deref() != Some(fill) => {
+                        ws.get_style_mut((col, row_num)).set_background_color(fill);
+                    }
+                    None if current.as_deref().is_some_and(|c| c == changed_fill || c == new_fill) => {
+                        ws.get_style_mut((col, row_num)).remove_fill();
+                    }
+                    _ => {}
+                }
+            }
+
+            // Rows removed upstream are struck through; rows that came back are not
+            if removed_mark.is_some() {
+                let was_struck = ws
+                    .get_cell((col, row_num))
+                    .and_then(|c| c.get_style().get_font())
+                    .is_some_and(|f| *f.get_strikethrough());
+                if struck || was_struck {
+                    ws.get_style_mut((col, row_num)).get_font_mut().set_strikethrough(struck);
+                }
+            }
+
+            // Column format: new cells always, existing ones only when asked
+            if let Some(fmt) = formats.get(key) {
+                let is_new = row_num > prev_last_row || ws.get_cell((col, row_num)).is_none();
+                if format_existing || is_new {
+                    fmt.apply(ws.get_cell_mut((col, row_num)));
+                }
+            }
+
+            // Key absent from the row (e.g. replaced by an input row without it):
+            // don't leave whatever an earlier run wrote at this position
+            if !rowmap.contains_key(key) {
+                let stale = ws.get_cell((col, row_num)).is_some_and(|c| {
+                    !c.get_value().is_empty() || c.is_formula() || c.get_hyperlink().is_some()
+                });
+                if stale {
+                    ws.get_cell_mut((col, row_num)).set_value("");
+                    if ws.get_cell((col, row_num)).is_some_and(|c| c.get_hyperlink().is_some()) {
+                        unlink_cell(ws, col, row_num);
+                    }
+                }
+            }
+
+            if let Some(v) = rowmap.get(key) {
+                // Resolve the link (URL + display text) for this row, if any
+                let link = hyperlink_map.get(key).and_then(|t| t.render(key, rowmap));
+                // a native link left over from an earlier run must not outlive its value
+                let keep_link = native_links && link.is_some();
+                if !keep_link
+                    && ws
+                        .get_cell((col, row_num))
+                        .is_some_and(|c| c.get_hyperlink().is_some())
+                {
+                    unlink_cell(ws, col, row_num);
+                }
+                let cell = ws.get_cell_mut((col, row_num));
+
+                if let Some((url, text)) = link {
+                    if native_links {
+                        // Plain value (or display template) plus a worksheet hyperlink relationship
+                        match text {
+                            Some(t) => set_text_value(cell, &t),
+                            None => {
+                                write_value(cell, v);
+                            }
+                        }
+                        cell.get_hyperlink_mut().set_url(url);
+                    } else {
+                        // HYPERLINK formula, with the text as cached result so
+                        // viewers (and our readback) that don't recalc still see it
+                        let text = text.unwrap_or_else(|| value_to_text(v));
+                        let f = format!(
+                            "HYPERLINK(\"{}\",\"{}\")",
+                            xl_quote_escape(&url),
+                            xl_quote_escape(&text)
+                        );
+                        cell.set_formula(&f);
+                        cell.set_formula_result_default(&text);
+                    }
+                    continue;
+                }
+
+                // Normal write for non-hyperlink columns (and empty link values)
+                match types.get(key) {
+                    Some(ColumnType::Text) => {
+                        cell.set_value_string(value_to_text(v));
+                    }
+                    Some(ty @ (ColumnType::Date | ColumnType::DateTime)) => {
+                        write_value(cell, v);
+                        if v.is_number() {
+                            let code = if *ty == ColumnType::Date { date_format } else { datetime_format };
+                            ensure_number_format(cell, code);
+                        }
+                    }
+                    _ => {
+                        write_value(cell, v);
+                    }
+                }
+            }
+        }
+    }
+
+    // Stale cells beyond the written rectangle (dropped columns, fewer rows)
+    let last_col = columns.len() as u32;
+    match clear_mode {
+        "values" => blank_outside(ws, last_col, last_row),
+        "remove" => {
+            let (high_col, high_row) = ws.get_highest_column_and_row();
+            if high_row > last_row {
+                ws.remove_row(&(last_row + 1), &(high_row - last_row));
+            }
+            if high_col > last_col {
+                ws.remove_column_by_index(&(last_col + 1), &(high_col - last_col));
+            }
+        }
+        // rows dropped by --sync must not be read back next run
+        _ /* none */ if trim_rows => blank_outside(ws, u32::MAX, last_row),
+        _ /* none */ => {}
+    }
+
+    // Sheet view: frozen panes, filter buttons, column widths
+    if let Some(at) = freeze {
+        freeze_panes(ws, at);
+    }
+    // a table brings its own filter buttons; Excel rejects a sheet filter overlapping it
+    if table.is_some() {
+        ws.remove_auto_filter();
+    } else if autofilter == Some(true) && last_col > 0 {
+        let last = umya::helper::coordinate::coordinate_from_index(&last_col, &last_row);
+        ws.set_auto_filter(format!("A1:{}", last));
+    } else if autofilter == Some(false) {
+        ws.remove_auto_filter();
+    }
+    let widths = match auto_width {
+        Some(range) => {
+            let buttons = autofilter == Some(true) || table.is_some();
+            auto_fit_columns(ws, columns, last_row, range, buttons, &recorded_by_col)
+        }
+        None => recorded_widths,
+    };
+
+    if let Some(spec) = table {
+        let headers: Vec<String> = columns
+            .iter()
+            .map(|c| labels.get(c).unwrap_or(c).clone())
+            .collect();
+        upsert_table(book, sheet_name, spec, &headers, last_row)?;
+    }
+
+    if let Some((archive_sheet, archived)) = archive {
+        if !archived.is_empty() {
+            append_archive(book, archive_sheet, columns, archived, opts)?;
+        }
+    }
+
+    if let Some((changes_sheet, changes)) = change_log {
+        if !changes.is_empty() {
+            append_change_log(book, changes_sheet, sheet_name, changes, opts)?;
+        }
+    }
+
+    if let Some(spec) = snapshot {
+        write_snapshot(book, spec, sheet_name, columns, rows, opts)?;
+    }
+
+    let link_texts: HashMap<String, String> = hyperlink_map
+        .iter()
+        .filter_map(|(k, t)| Some((k.clone(), t.text.clone()?)))
+        .collect();
+    write_column_meta(book, sheet_name, columns, labels, &link_texts, &widths, split_value);
+    Ok(())
+}
+
+// Blank the values (keeping the cells, so their styles survive) right of
+// `last_col` or below `last_row`
+fn blank_outside(ws: &mut umya::Worksheet, last_col: u32, last_row: u32) {
+    let mut linked: Vec<(u32, u32)> = Vec::new();
+    for cell in ws.get_cell_collection_mut() {
+        let coord = cell.get_coordinate();
+        let (c, r) = (*coord.get_col_num(), *coord.get_row_num());
+        if c > last_col || r > last_row {
+            cell.set_value("");
+            if cell.get_hyperlink().is_some() {
+                linked.push((c, r));
+            }
+        }
+    }
+    for (c, r) in linked {
+        unlink_cell(ws, c, r);
+    }
+}
+
+// Append rows dropped by `--sync archive` below the archive sheet's data, with an
+// `_archived_at` timestamp. Its header grows with keys it hasn't seen yet.
+fn append_archive(
+    book: &mut umya::Spreadsheet,
+    archive_sheet: &str,
+    columns: &[String],
+    rows: &[RowMap],
+    opts: &WriteOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if book.get_sheet_by_name(archive_sheet).is_none() {
+        book.new_sheet(archive_sheet)?;
+    }
+    let ws = book
+        .get_sheet_by_name_mut(archive_sheet)
+        .expect("sheet must exist");
+
+    let header = side_sheet_header(ws, "_archived_at", columns, opts.labels);
+    let now = Value::from(excel_serial(Local::now().naive_local()));
+    let first_row = ws.get_highest_row().max(1) + 1;
+    for (r_idx, row) in rows.iter().enumerate() {
+        let stamp = ("_archived_at", &now, opts.datetime_format);
+        write_plain_row(ws, first_row + r_idx as u32, &header, row, Some(stamp), opts);
+    }
+    Ok(())
+}
+
+// Header of an append-only side sheet: the keys already there (labels mapped
+// back), then `lead` and any of `columns` it doesn't have yet. Rewrites row 1.
+fn side_sheet_header(
+    ws: &mut umya::Worksheet,
+    lead: &str,
+    columns: &[String],
+    labels: &HashMap<String, String>,
+) -> Vec<String> {
+    let key_of_label: HashMap<&str, &str> = labels.iter().map(|(k, l)| (l.as_str(), k.as_str())).collect();
+    let high_col = ws.get_highest_column();
+    let mut header: Vec<String> = (1..=high_col)
+        .map(|c| ws.get_value((c, 1)))
+        .map(|h| key_of_label.get(h.as_str()).map(|k| k.to_string()).unwrap_or(h))
+        .collect();
+    for key in std::iter::once(lead).chain(columns.iter().map(|c| c.as_str())) {
+        if !header.iter().any(|h| h == key) {
+            header.push(key.to_string());
+        }
+    }
+    for (c_idx, key) in header.iter().enumerate() {
+        let label = labels.get(key).unwrap_or(key);
+        ws.get_cell_mut((c_idx as u32 + 1, 1)).set_value_string(label);
+    }
+    header
+}
+
+// Plain values (no links, no [format]) for side sheets, typed like the main
+// sheet; `stamp` is a tool-owned (key, value, number format) column
+fn write_plain_row(
+    ws: &mut umya::Worksheet,
+    row_num: u32,
+    header: &[String],
+    row: &RowMap,
+    stamp: Option<(&str, &Value, &str)>,
+    opts: &WriteOptions,
+) {
+    for (c_idx, key) in header.iter().enumerate() {
+        let cell = ws.get_cell_mut((c_idx as u32 + 1, row_num));
+        if let Some((_, v, code)) = stamp.filter(|(k, _, _)| k == key) {
+            write_value(cell, v);
+            ensure_number_format(cell, code);
+            continue;
+        }
+        let Some(v) = row.get(key) else {
+            continue;
+        };
+        match opts.types.get(key) {
+            Some(ColumnType::Text) => {
+                cell.set_value_string(value_to_text(v));
+            }
+            Some(ty @ (ColumnType::Date | ColumnType::DateTime)) => {
+                write_value(cell, v);
+                if v.is_number() {
+                    let code = if *ty == ColumnType::Date { opts.date_format } else { opts.datetime_format };
+                    ensure_number_format(cell, code);
+                }
+            }
+            _ => {
+                write_value(cell, v);
+            }
+        }
+    }
+}
+
+// Native write of a JSON value, keeping the cell's style
+fn write_value(cell: &mut umya::Cell, v: &Value) {
+    match v {
+        Value::Null => {
+            cell.set_value("");
+        }
+        Value::Bool(b) => {
+            cell.set_value_bool(*b);
+        }
+        Value::Number(n) => {
+            if let Some(f) = n.as_f64() {
+                cell.set_value_number(f);
+            } else {
+                cell.set_value(n.to_string());
+            }
+        }
+        Value::String(s) => {
+            set_text_value(cell, s);
+        }
+        other => {
+            cell.set_value(other.to_string());
+        }
+    }
+}
+
+// ---------------- Change tracking ----------------
+
+// What this run's merge did to a sheet row
+#[derive(Clone, Default, PartialEq)]
+enum RowChange {
+    #[default]
+    Untouched, // not in the input
+    Unchanged, // in the input, same values
+    Added,
+    Updated(Vec<String>), // changed columns
+}
+
+struct Change {
+    pk: String,
+    column: String,
+    old: Value,
+    new: Value,
+}
+
+// (column, old, new) for every key whose value differs; missing keys count as
+// empty. Numbers compare by value, so 42 from JSON equals 42.0 read back.
+fn diff_rows(old: &RowMap, new: &RowMap) -> Vec<(String, Value, Value)> {
+    let keys: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    keys.into_iter()
+        .filter_map(|k| {
+            let a = old.get(k).unwrap_or(&Value::Null);
+            let b = new.get(k).unwrap_or(&Value::Null);
+            (!values_equal(a, b)).then(|| (k.clone(), a.clone(), b.clone()))
+        })
+        .collect()
+}
+
+fn values_equal(a: &Value, b: &Value) -> bool {
+    if is_blank(a) && is_blank(b) {
+        return true;
+    }
+    match (a.as_f64(), b.as_f64()) {
+        (Some(x), Some(y)) => (x - y).abs() < 1e-9,
+        _ => value_to_text(a) == value_to_text(b),
+    }
+}
+
+// PK values as shown to people: "ABC-1" or "ABC-1 / 3"
+fn pk_display(row: &RowMap, pk_cols: &[String]) -> String {
+    pk_cols
+        .iter()
+        .map(|k| row.get(k).map(value_to_text).unwrap_or_default())
+        .collect::<Vec<_>>()
+        .join(" / ")
+}
+
+// One row per change: timestamp | sheet | pk | column | old | new
+fn append_change_log(
+    book: &mut umya::Spreadsheet,
+    changes_sheet: &str,
+    sheet_name: &str,
+    changes: &[Change],
+    opts: &WriteOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if book.get_sheet_by_name(changes_sheet).is_none() {
+        book.new_sheet(changes_sheet)?;
+    }
+    let ws = book
+        .get_sheet_by_name_mut(changes_sheet)
+        .expect("sheet must exist");
+    for (c, h) in ["timestamp", "sheet", "pk", "column", "old", "new"].iter().enumerate() {
+        ws.get_cell_mut((c as u32 + 1, 1)).set_value_string(*h);
+    }
+
+    let now = excel_serial(Local::now().naive_local());
+    let first_row = ws.get_highest_row().max(1) + 1;
+    for (i, ch) in changes.iter().enumerate() {
+        let r = first_row + i as u32;
+        let cell = ws.get_cell_mut((1, r));
+        cell.set_value_number(now);
+        ensure_number_format(cell, opts.datetime_format);
+        ws.get_cell_mut((2, r)).set_value_string(sheet_name);
+        ws.get_cell_mut((3, r)).set_value_string(&ch.pk);
+        let column = opts.labels.get(&ch.column).unwrap_or(&ch.column);
+        ws.get_cell_mut((4, r)).set_value_string(column);
+        write_value(ws.get_cell_mut((5, r)), &ch.old);
+        write_value(ws.get_cell_mut((6, r)), &ch.new);
+    }
+    Ok(())
+}
+
+// ---------------- Audit columns ----------------
+
+const AUDIT_COLUMNS: [&str; 3] = ["_first_seen", "_last_updated", "_last_seen"];
+
+// Rows in the input are seen now; new rows were first seen (and updated) now,
+// matched rows only count as updated when their content changed.
+fn stamp_audit(row: &mut RowMap, change: &RowChange, now: &Value) {
+    if *change == RowChange::Untouched {
+        return;
+    }
+    // rows from before audit was switched on get their first sighting now
+    if row.get("_first_seen").is_none_or(is_blank) {
+        row.insert("_first_seen".to_string(), now.clone());
+    }
+    if matches!(change, RowChange::Added | RowChange::Updated(_)) || row.get("_last_updated").is_none_or(is_blank) {
+        row.insert("_last_updated".to_string(), now.clone());
+    }
+    row.insert("_last_seen".to_string(), now.clone());
+}
+
+// ---------------- History snapshots ----------------
+
+struct SnapshotSpec {
+    history: bool,         // append to `history_sheet` instead of a dated sheet
+    name: String,          // dated sheet name: {sheet}, {date}
+    history_sheet: String,
+    keep: Option<usize>,   // last N snapshots (dates)
+    date: NaiveDate,       // this run's snapshot date
+}
+
+impl SnapshotSpec {
+    // today's dated sheet for `sheet_name`
+    fn dated_sheet(&self, sheet_name: &str) -> String {
+        snapshot_sheet_name(&self.name, sheet_name, &self.date.format("%Y-%m-%d").to_string())
+    }
+}
+
+// Copy this run's result (`columns` x `rows`) to today's snapshot; a re-run on
+// the same day replaces it. Older snapshots beyond `keep` are dropped.
+fn write_snapshot(
+    book: &mut umya::Spreadsheet,
+    spec: &SnapshotSpec,
+    sheet_name: &str,
+    columns: &[String],
+    rows: &[RowMap],
+    opts: &WriteOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if spec.history {
+        return append_history(book, spec, sheet_name, columns, rows, opts);
+    }
+
+    let name = spec.dated_sheet(sheet_name);
+    if name == sheet_name {
+        return Err(format!("Snapshot sheet name `{}` is the target sheet", name).into());
+    }
+    let _ = book.remove_sheet_by_name(&name);
+    let ws = book.new_sheet(&name)?;
+    for (c_idx, key) in columns.iter().enumerate() {
+        let label = opts.labels.get(key).unwrap_or(key);
+        ws.get_cell_mut((c_idx as u32 + 1, 1)).set_value_string(label);
+    }
+    for (r_idx, row) in rows.iter().enumerate() {
+        write_plain_row(ws, r_idx as u32 + 2, columns, row, None, opts);
+    }
+
+    // Retention: our snapshot sheets are the ones the template produces for some date
+    if let Some(keep) = spec.keep {
+        const PROBE: &str = "0000-00-00";
+        let probe = snapshot_sheet_name(&spec.name, sheet_name, PROBE);
+        let pattern = probe
+            .split(PROBE)
+            .map(regex::escape)
+            .collect::<Vec<_>>()
+            .join(r"(\d{4}-\d{2}-\d{2})");
+        let re = Regex::new(&format!("^{}$", pattern))?;
+        let mut snapshots: Vec<(String, String)> = book
+            .get_sheet_collection()
+            .iter()
+            .filter_map(|ws| {
+                let caps = re.captures(ws.get_name())?;
+                Some((caps[1].to_string(), ws.get_name().to_string()))
+            })
+            .collect();
+        snapshots.sort_by(|a, b| b.cmp(a)); // newest first
+        for (_, old) in snapshots.into_iter().skip(keep) {
+            let _ = book.remove_sheet_by_name(&old);
+        }
+    }
+    Ok(())
+}
+
+// "{sheet} {date}" -> "Jira 2026-10-16". A sheet part cut to fit Excel's 31
+// characters ends in a hash of the full name ("Long proje~3f2a 2026-10-16"),
+// so sheets that only differ past the cut keep apart.
+fn snapshot_sheet_name(template: &str, sheet_name: &str, date: &str) -> String {
+    let fixed = template.replace("{sheet}", "").replace("{date}", date).chars().count();
+    let room = 31usize.saturating_sub(fixed);
+    let sheet: String = if sheet_name.chars().count() <= room {
+        sheet_name.to_string()
+    } else {
+        // FNV-1a: stable across runs and builds, unlike std's hasher
+        let hash = sheet_name
+            .bytes()
+            .fold(0x811c_9dc5u32, |h, b| (h ^ u32::from(b)).wrapping_mul(0x0100_0193));
+        let tag = format!("~{:04x}", hash & 0xffff);
+        let head: String = sheet_name.chars().take(room.saturating_sub(tag.len())).collect();
+        format!("{}{}", head, tag).chars().take(room).collect()
+    };
+    template.replace("{sheet}", &sheet).replace("{date}", date)
+}
+
+// Long format: every run appends its rows with a `_snapshot` date column and
+// the `_sheet` they come from, so several sheets can share one History sheet
+fn append_history(
+    book: &mut umya::Spreadsheet,
+    spec: &SnapshotSpec,
+    sheet_name: &str,
+    columns: &[String],
+    rows: &[RowMap],
+    opts: &WriteOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    const SNAPSHOT: &str = "_snapshot";
+    const SHEET: &str = "_sheet";
+    if book.get_sheet_by_name(&spec.history_sheet).is_none() {
+        book.new_sheet(&spec.history_sheet)?;
+    }
+    let ws = book
+        .get_sheet_by_name_mut(&spec.history_sheet)
+        .expect("sheet must exist");
+    let columns: Vec<String> = std::iter::once(SHEET.to_string()).chain(columns.iter().cloned()).collect();
+    let header = side_sheet_header(ws, SNAPSHOT, &columns, opts.labels);
+    let snap_col = header.iter().position(|h| h == SNAPSHOT).expect("lead column") as u32 + 1;
+    let sheet_col = header.iter().position(|h| h == SHEET).expect("sheet column") as u32 + 1;
+
+    // Drop this sheet's rows of today (same-day re-run) and its snapshots older
+    // than the last `keep`; rows without a `_sheet` predate it and count as ours
+    let today_serial = excel_serial(spec.date.and_hms_opt(0, 0, 0).expect("midnight"));
+    let row_dates: Vec<(u32, i64)> = (2..=ws.get_highest_row())
+        .filter(|&r| {
+            let from = ws.get_value((sheet_col, r));
+            from.is_empty() || from == sheet_name
+        })
+        .filter_map(|r| {
+            let d = ws.get_value((snap_col, r)).parse::<f64>().ok()?;
+            Some((r, d.floor() as i64))
+        })
+        .collect();
+    let today_day = today_serial as i64;
+    let mut older: Vec<i64> = row_dates.iter().map(|(_, d)| *d).filter(|d| *d != today_day).collect();
+    older.sort_unstable_by(|a, b| b.cmp(a));
+    older.dedup();
+    let kept_older: HashSet<i64> = match spec.keep {
+        Some(keep) => older.into_iter().take(keep - 1).collect(), // today's run is one of them
+        None => older.into_iter().collect(),
+    };
+    let drop: Vec<u32> = row_dates
+        .iter()
+        .filter(|(_, d)| !kept_older.contains(d))
+        .map(|(r, _)| *r)
+        .collect();
+    // remove contiguous blocks bottom-up so row numbers above stay valid
+    let mut blocks: Vec<(u32, u32)> = Vec::new(); // (first row, count)
+    for r in drop {
+        match blocks.last_mut() {
+            Some((first, count)) if *first + *count == r => *count += 1,
+            _ => blocks.push((r, 1)),
+        }
+    }
+    for (first, count) in blocks.into_iter().rev() {
+        ws.remove_row(&first, &count);
+    }
+
+    let stamp_value = Value::from(today_serial);
+    let first_row = ws.get_highest_row().max(1) + 1;
+    for (r_idx, row) in rows.iter().enumerate() {
+        let mut row = row.clone();
+        row.insert(SHEET.to_string(), Value::String(sheet_name.to_string()));
+        let stamp = (SNAPSHOT, &stamp_value, opts.date_format);
+        write_plain_row(ws, first_row + r_idx as u32, &header, &row, Some(stamp), opts);
+    }
+    Ok(())
+}
+
+// ---------------- Dry run ----------------
+
+// What a run would do to the sheet, for --dry-run
+struct DryRunReport<'a> {
+    out: &'a str,
+    sheet: &'a str,
+    rows: &'a [RowMap],
+    row_changes: &'a [RowChange],
+    pk_keys: &'a [String],
+    matched: usize,   // existing rows whose PK is in the input
+    untouched: usize, // existing rows not in the input, kept as they are
+    removed: &'a [String],
+    marked: &'a [String],
+    columns_added: Vec<String>,
+    columns_dropped: Vec<String>,
+}
+
+impl DryRunReport<'_> {
+    // rows are named by PK, or by their sheet row without one
+    fn row_name(&self, i: usize) -> String {
+        if self.pk_keys.is_empty() {
+            format!("row {}", i + 2)
+        } else {
+            pk_display(&self.rows[i], self.pk_keys)
+        }
+    }
+
+    fn added(&self) -> Vec<String> {
+        (0..self.rows.len())
+            .filter(|&i| self.row_changes[i] == RowChange::Added)
+            .map(|i| self.row_name(i))
+            .collect()
+    }
+
+    fn updated(&self) -> Vec<(String, &[String])> {
+        (0..self.rows.len())
+            .filter_map(|i| match &self.row_changes[i] {
+                RowChange::Updated(cols) => Some((self.row_name(i), cols.as_slice())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn to_json(&self) -> Value {
+        let updated = self.updated();
+        let added = self.added();
+        serde_json::json!({
+            "out": self.out,
+            "sheet": self.sheet,
+            "rows": {
+                "added": added.len(),
+                "updated": updated.len(),
+                "unchanged": self.matched - updated.len(),
+                "untouched": self.untouched,
+                "removed": self.removed.len(),
+                "marked": self.marked.len(),
+            },
+            "added": added,
+            "updated": updated
+                .iter()
+                .map(|(pk, cols)| serde_json::json!({ "pk": pk, "columns": cols }))
+                .collect::<Vec<_>>(),
+            "removed": self.removed,
+            "marked": self.marked,
+            "columns": {
+                "added": self.columns_added,
+                "dropped": self.columns_dropped,
+            },
+        })
+    }
+
+    fn print_text(&self) {
+        let updated = self.updated();
+        let added = self.added();
+        println!("Dry run: {} [{}] (nothing written)", self.out, self.sheet);
+        println!(
+            "rows:    {} added, {} updated, {} unchanged, {} untouched, {} removed, {} marked",
+            added.len(),
+            updated.len(),
+            self.matched - updated.len(),
+            self.untouched,
+            self.removed.len(),
+            self.marked.len()
+        );
+        let cols: Vec<String> = self
+            .columns_added
+            .iter()
+            .map(|c| format!("+{}", c))
+            .chain(self.columns_dropped.iter().map(|c| format!("-{}", c)))
+            .collect();
+        if !cols.is_empty() {
+            println!("columns: {}", cols.join(", "));
+        }
+        if !added.is_empty() {
+            println!("added:   {}", added.join(", "));
+        }
+        for (pk, cols) in &updated {
+            println!("updated: {} ({})", pk, cols.join(", "));
+        }
+        if !self.removed.is_empty() {
+            println!("removed: {}", self.removed.join(", "));
+        }
+        if !self.marked.is_empty() {
+            println!("marked:  {}", self.marked.join(", "));
         }
     }
+}
 
-    // -------- write/update XLSX while preserving formatting --------
-    write_xlsx_preserve(&out_path, &sheet_name, &columns, &existing_rows, &hyperlink_map)?;
-    Ok(())
+// ---------------- Excel Table ----------------
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum TableRule {
+    Name(String),
+    Full {
+        name: String,
+        style: Option<String>,
+        row_stripes: Option<bool>,
+        column_stripes: Option<bool>,
+        first_column: Option<bool>,
+        last_column: Option<bool>,
+    },
 }
 
-// ---------------- helpers: ordering adders ----------------
+struct TableSpec {
+    name: String,
+    style: Option<String>, // None: keep an existing table's style (default TableStyleMedium2)
+    row_stripes: bool,
+    column_stripes: bool,
+    first_column: bool,
+    last_column: bool,
+}
 
-fn add_exact<F: Fn(&str) -> bool>(
-    columns: &mut Vec<String>,
-    seen: &mut HashSet<String>,
-    names: &[String],
-    key_allowed: &F,
-) {
-    for name in names {
-        if key_allowed(name) {
-            push_unique(columns, seen, name.clone());
+impl TableSpec {
+    fn from_rule(rule: &TableRule) -> Self {
+        match rule {
+            TableRule::Name(name) => TableSpec {
+                name: name.clone(),
+                style: None,
+                row_stripes: true,
+                column_stripes: false,
+                first_column: false,
+                last_column: false,
+            },
+            TableRule::Full {
+                name,
+                style,
+                row_stripes,
+                column_stripes,
+                first_column,
+                last_column,
+            } => TableSpec {
+                name: name.clone(),
+                style: style.clone(),
+                row_stripes: row_stripes.unwrap_or(true),
+                column_stripes: column_stripes.unwrap_or(false),
+                first_column: first_column.unwrap_or(false),
+                last_column: last_column.unwrap_or(false),
+            },
         }
     }
-}
 
-fn add_regex(
-    columns: &mut Vec<String>,
-    seen: &mut HashSet<String>,
-    universe: &[String],
-    pats: &[Regex],
-) {
-    for re in pats {
-        for k in universe.iter().filter(|k| re.is_match(k)) {
-            push_unique(columns, seen, k.clone());
-        }
+    fn style_info(&self, name: &str) -> umya::TableStyleInfo {
+        umya::TableStyleInfo::new(
+            name,
+            self.first_column,
+            self.last_column,
+            self.row_stripes,
+            self.column_stripes,
+        )
     }
 }
 
-fn add_substr(
-    columns: &mut Vec<String>,
-    seen: &mut HashSet<String>,
-    universe: &[String],
-    subs: &[String],
-) {
-    for sub in subs {
-        for k in universe.iter().filter(|k| k.contains(sub)) {
-            push_unique(columns, seen, k.clone());
-        }
+// Excel table names: letter/underscore first, then letters, digits, `_` or `.`
+fn validate_table_name(name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    static RE: OnceLock<(Regex, Regex)> = OnceLock::new();
+    let (valid, cell_ref) = RE.get_or_init(|| {
+        (
+            Regex::new(r"^[A-Za-z_\\][A-Za-z0-9_.\\]{0,254}$").expect("static regex"),
+            // A1 or R1C1 style references (also a bare R or C), which Excel rejects
+            Regex::new(r"(?i)^(?:[a-z]{1,3}[0-9]+|r[0-9]*c?[0-9]*|c[0-9]*)$").expect("static regex"),
+        )
+    });
+    if !valid.is_match(name) {
+        return Err(format!("Invalid table name `{}` (letters, digits, `_` and `.`; no spaces)", name).into());
+    }
+    if cell_ref.is_match(name) {
+        return Err(format!("Invalid table name `{}` (looks like a cell reference)", name).into());
     }
+    Ok(())
 }
 
-// ---------------- Parsing ----------------
-
-fn parse_json_array(input: &str) -> Result<Vec<HashMap<String, Value>>, Box<dyn std::error::Error>> {
-    let v: Value = serde_json::from_str(input)?;
-    match v {
-        Value::Array(arr) => arr.into_iter().map(value_to_rowmap).collect(),
-        Value::Object(obj) => Ok(vec![object_to_rowmap(obj)]),
-        _ => Err("Expected a JSON array of objects or a single object".into()),
+// Create the table over header row + data rows, or resize/re-column an existing one
+fn upsert_table(
+    book: &mut umya::Spreadsheet,
+    sheet_name: &str,
+    spec: &TableSpec,
+    headers: &[String],
+    last_row: u32,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if headers.is_empty() {
+        return Ok(());
+    }
+    let taken_elsewhere = book.get_sheet_collection().iter().any(|ws| {
+        ws.get_name() != sheet_name
+            && ws.get_tables().iter().any(|t| t.get_name().eq_ignore_ascii_case(&spec.name))
+    });
+    if taken_elsewhere {
+        return Err(format!("Table name `{}` is already used on another sheet", spec.name).into());
+    }
+    // table column names must be unique, ignoring case
+    let mut seen: HashSet<String> = HashSet::new();
+    if let Some(dup) = headers.iter().find(|h| !seen.insert(h.to_lowercase())) {
+        return Err(format!("Table `{}` would have the column `{}` twice", spec.name, dup).into());
     }
-}
 
-fn parse_ndjson(input: &str) -> Result<Vec<HashMap<String, Value>>, Box<dyn std::error::Error>> {
-    let mut rows = Vec::new();
-    for (lineno, line) in input.lines().enumerate() {
-        let line = line.trim();
-        if line.is_empty() {
-            continue;
-        }
-        let v: Value = serde_json::from_str(line)
-            .map_err(|e| format!("Invalid JSON on line {}: {}", lineno + 1, e))?;
-        rows.push(value_to_rowmap(v)?);
+    let ws = book
+        .get_sheet_by_name_mut(sheet_name)
+        .expect("sheet must exist");
+    // a table needs at least one data row, even an empty one
+    let area = ((1u32, 1u32), (headers.len() as u32, last_row.max(2)));
+    let mut table = umya::Table::new(&spec.name, area);
+    for h in headers {
+        table.add_column(umya::TableColumn::new(h));
     }
-    Ok(rows)
-}
 
-fn value_to_rowmap(v: Value) -> Result<HashMap<String, Value>, Box<dyn std::error::Error>> {
-    match v {
-        Value::Object(obj) => Ok(object_to_rowmap(obj)),
-        _ => Err("Each record must be a JSON object (already flattened)".into()),
+    // another table in the way: Excel refuses overlapping tables
+    let ((c1, r1), (c2, r2)) = area;
+    let overlapping = ws.get_tables().iter().find(|t| {
+        let (from, to) = t.get_area();
+        !t.get_name().eq_ignore_ascii_case(&spec.name)
+            && *from.get_col_num() <= c2
+            && *to.get_col_num() >= c1
+            && *from.get_row_num() <= r2
+            && *to.get_row_num() >= r1
+    });
+    if let Some(other) = overlapping {
+        return Err(format!(
+            "Table `{}` would overlap table `{}` on sheet `{}`",
+            spec.name,
+            other.get_name(),
+            sheet_name
+        )
+        .into());
     }
-}
 
-fn object_to_rowmap(obj: JsonMap<String, Value>) -> HashMap<String, Value> {
-    obj.into_iter().collect()
+    let tables = ws.get_tables_mut();
+    let existing = tables.iter().position(|t| t.get_name().eq_ignore_ascii_case(&spec.name));
+    let style = match (&spec.style, existing) {
+        (Some(style), _) => Some(spec.style_info(style)),
+        (None, Some(i)) => tables[i].get_style_info().cloned(),
+        (None, None) => Some(spec.style_info("TableStyleMedium2")),
+    };
+    table.set_style_info(style);
+    match existing {
+        Some(i) => tables[i] = table,
+        None => tables.push(table),
+    }
+    Ok(())
 }
 
-// ---------------- Read existing values (calamine) ----------------
+// ---------------- Column metadata ----------------
 
-fn read_existing_xlsx_values(
-    path: &str,
-    sheet_name: &str,
-) -> Result<(Vec<String>, Vec<HashMap<String, Value>>), Box<dyn std::error::Error>> {
-    let mut wb = open_workbook_auto(path)?;
-    let maybe_range = wb.worksheet_range(sheet_name);
+// Hidden sheet recording, per sheet, which key sits behind each display label
+// (so PK merge and ordering still find their columns after a rename), which
+// link text template a column's cells show (to read the value back), which
+// column widths the tool itself set (so hand-sized columns are left alone) and,
+// on `sheet_by` sheets, the JSON of the value the sheet was generated for.
+const META_SHEET: &str = "_columns";
 
-    let range = match maybe_range {
-        Some(Ok(r)) => r,
-        Some(Err(e)) => return Err(Box::<dyn std::error::Error>::from(e)),
-        None => return Ok((Vec::new(), Vec::new())),
+// key -> width the tool applied on an earlier run
+fn read_meta_widths(book: &umya::Spreadsheet, sheet_name: &str) -> HashMap<String, f64> {
+    let Some(meta) = book.get_sheet_by_name(META_SHEET) else {
+        return HashMap::new();
     };
+    let (_, high_row) = meta.get_highest_column_and_row();
+    (2..=high_row)
+        .filter(|&r| meta.get_value((1, r)) == sheet_name)
+        .filter_map(|r| {
+            let width = meta.get_value((5, r)).parse::<f64>().ok()?;
+            Some((meta.get_value((2, r)), width))
+        })
+        .collect()
+}
 
-    let mut rows_iter = range.rows();
-    let header_cells = match rows_iter.next() {
-        Some(r) => r.to_vec(),
-        None => return Ok((Vec::new(), Vec::new())),
+// `sheet_by` value -> sheet it was written to, for column `col`
+fn read_meta_split(book: &umya::Spreadsheet, col: &str) -> HashMap<Option<String>, String> {
+    let Some(meta) = book.get_sheet_by_name(META_SHEET) else {
+        return HashMap::new();
     };
+    let (_, high_row) = meta.get_highest_column_and_row();
+    (2..=high_row)
+        .filter(|&r| meta.get_value((2, r)) == col && !meta.get_value((1, r)).is_empty())
+        .filter_map(|r| {
+            let value = serde_json::from_str::<Option<String>>(&meta.get_value((6, r))).ok()?;
+            Some((value, meta.get_value((1, r))))
+        })
+        .collect()
+}
 
-    let headers: Vec<String> = header_cells.iter().map(cell_to_string).collect();
+// display label -> key, as recorded when the sheet was written
+fn read_meta_labels(book: &umya::Spreadsheet, sheet_name: &str) -> HashMap<String, String> {
+    let Some(meta) = book.get_sheet_by_name(META_SHEET) else {
+        return HashMap::new();
+    };
+    let (_, high_row) = meta.get_highest_column_and_row();
+    (2..=high_row)
+        .filter(|&r| meta.get_value((1, r)) == sheet_name && !meta.get_value((3, r)).is_empty())
+        .map(|r| (meta.get_value((3, r)), meta.get_value((2, r))))
+        .collect()
+}
 
-    let mut rows: Vec<HashMap<String, Value>> = Vec::new();
-    for r in rows_iter {
-        let mut map = HashMap::new();
-        for (i, cell) in r.iter().enumerate() {
-            if let Some(col) = headers.get(i) {
-                if col.trim().is_empty() {
-                    continue;
-                }
-                let s = cell_to_string(cell);
-                if !s.is_empty() {
-                    map.insert(col.clone(), Value::String(s));
-                } else {
-                    map.insert(col.clone(), Value::Null);
-                }
-            }
+fn write_column_meta(
+    book: &mut umya::Spreadsheet,
+    sheet_name: &str,
+    columns: &[String],
+    labels: &HashMap<String, String>,
+    link_texts: &HashMap<String, String>,
+    widths: &HashMap<String, f64>,
+    split_value: Option<(&str, &str)>, // (sheet_by column, JSON of its value)
+) {
+    let nothing = labels.is_empty() && link_texts.is_empty() && widths.is_empty() && split_value.is_none();
+    if nothing && book.get_sheet_by_name(META_SHEET).is_none() {
+        return;
+    }
+    if book.get_sheet_by_name(META_SHEET).is_none() {
+        if let Ok(ws) = book.new_sheet(META_SHEET) {
+            ws.set_state(umya::SheetStateValues::Hidden);
         }
-        if map.values().any(|v| !v.is_null()) {
-            rows.push(map);
+    }
+    let Some(meta) = book.get_sheet_by_name_mut(META_SHEET) else {
+        return;
+    };
+
+    // keep other sheets' entries, replace ours
+    let (_, high_row) = meta.get_highest_column_and_row();
+    let mut entries: Vec<[String; 6]> = (2..=high_row)
+        .map(|r| {
+            [
+                meta.get_value((1, r)),
+                meta.get_value((2, r)),
+                meta.get_value((3, r)),
+                meta.get_value((4, r)),
+                meta.get_value((5, r)),
+                meta.get_value((6, r)),
+            ]
+        })
+        .filter(|e| e[0] != sheet_name && !e[0].is_empty())
+        .collect();
+    let split_key = split_value.map(|(col, _)| col);
+    for key in columns.iter().map(String::as_str).chain(split_key.filter(|k| !columns.iter().any(|c| c == k))) {
+        let label = labels.get(key).cloned().unwrap_or_default();
+        let link_text = link_texts.get(key).cloned().unwrap_or_default();
+        let width = widths.get(key).map(|w| w.to_string()).unwrap_or_default();
+        let split = split_value
+            .filter(|(col, _)| *col == key)
+            .map(|(_, v)| v.to_string())
+            .unwrap_or_default();
+        if !label.is_empty() || !link_text.is_empty() || !width.is_empty() || !split.is_empty() {
+            entries.push([sheet_name.to_string(), key.to_string(), label, link_text, width, split]);
         }
     }
 
-    Ok((headers, rows))
+    for r in 1..=high_row.max(1) {
+        for c in 1..=6 {
+            meta.remove_cell((c, r));
+        }
+    }
+    for (c, h) in ["sheet", "key", "label", "link_text", "width", "sheet_by_value"].iter().enumerate() {
+        meta.get_cell_mut((c as u32 + 1, 1)).set_value_string(*h);
+    }
+    for (i, e) in entries.iter().enumerate() {
+        for (c, v) in e.iter().enumerate() {
+            meta.get_cell_mut((c as u32 + 1, i as u32 + 2)).set_value_string(v);
+        }
+    }
 }
 
-fn cell_to_string(cell: &DataType) -> String {
-    match cell {
-        DataType::Empty => String::new(),
-        DataType::String(s) => s.to_string(),
-        DataType::Float(f) => {
-            if f.fract() == 0.0 {
-                format!("{}", *f as i64)
-            } else {
-                f.to_string()
+// ---------------- Sheet view: freeze, filter, widths ----------------
+
+// `freeze` setting -> top-left cell of the scrolling pane (col, row), 1-based
+fn resolve_freeze(
+    spec: &str,
+    columns: &[String],
+    pk_keys: &[String],
+) -> Result<(u32, u32), Box<dyn std::error::Error>> {
+    match spec.to_lowercase().as_str() {
+        "header" => Ok((1, 2)),
+        // header row plus the PK columns leading the sheet
+        "pk" => {
+            let leading = columns.iter().take_while(|c| pk_keys.contains(c)).count();
+            Ok((leading as u32 + 1, 2))
+        }
+        _ => {
+            let re = Regex::new(r"^([A-Za-z]{1,3})([0-9]+)$").expect("static regex");
+            let caps = re
+                .captures(spec)
+                .ok_or_else(|| format!("Invalid freeze `{}` (expected header|pk|cell like B2)", spec))?;
+            let col = umya::helper::coordinate::column_index_from_string(&caps[1]);
+            let row: u32 = caps[2].parse()?;
+            if row == 0 || (col, row) == (1, 1) {
+                return Err(format!("Invalid freeze `{}`: nothing above or left of it to freeze", spec).into());
             }
+            Ok((col, row))
         }
-        DataType::Int(i) => i.to_string(),
-        DataType::Bool(b) => b.to_string(),
-        DataType::Error(e) => format!("ERR:{:?}", e),
-        DataType::DateTime(v) => v.to_string(),
-        DataType::Duration(v) => v.to_string(),
-        DataType::DateTimeIso(s) => s.clone(),
-        DataType::DurationIso(s) => s.clone(),
     }
 }
 
-// ---------------- PK handling ----------------
+// Freeze everything above and left of `at`
+fn freeze_panes(ws: &mut umya::Worksheet, at: (u32, u32)) {
+    let views = ws.get_sheet_views_mut().get_sheet_view_list_mut();
+    if views.is_empty() {
+        views.push(umya::SheetView::default());
+    }
+    let view = &mut views[0];
+    // old selections point into the previous pane layout
+    view.get_selection_mut().clear();
 
-fn composite_pk(row: &HashMap<String, Value>, pk_cols: &[String]) -> Option<String> {
-    let mut parts: Vec<String> = Vec::with_capacity(pk_cols.len());
-    for c in pk_cols {
-        match row.get(c) {
-            Some(Value::String(s)) => parts.push(s.clone()),
-            Some(Value::Number(n)) => parts.push(n.to_string()),
-            Some(Value::Bool(b)) => parts.push(b.to_string()),
-            Some(Value::Null) | None => return None,
-            Some(other) => parts.push(other.to_string()),
-        }
+    let (col, row) = at;
+    let mut pane = umya::Pane::default();
+    if col > 1 {
+        pane.set_horizontal_split((col - 1) as f64);
     }
-    Some(parts.join("\u{1F}"))
+    if row > 1 {
+        pane.set_vertical_split((row - 1) as f64);
+    }
+    pane.get_top_left_cell_mut().set_col_num(col).set_row_num(row);
+    pane.set_active_pane(match (col > 1, row > 1) {
+        (true, true) => umya::PaneValues::BottomRight,
+        (true, false) => umya::PaneValues::TopRight,
+        _ => umya::PaneValues::BottomLeft,
+    });
+    pane.set_state(umya::PaneStateValues::Frozen);
+    view.set_pane(pane);
 }
 
-// ---------------- Write XLSX while preserving formatting ----------------
-
-fn write_xlsx_preserve(
-    out_path: &str,
-    sheet_name: &str,
+// Fit each column to its longest displayed line, clamped to [min, max].
+// Columns with a width the tool didn't set (`recorded`) were sized by hand and
+// are skipped. Returns the widths to record for the next run.
+fn auto_fit_columns(
+    ws: &mut umya::Worksheet,
     columns: &[String],
-    rows: &[HashMap<String, Value>],
-    hyperlink_map: &HashMap<String, String>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    // Open existing workbook or create a new one
-    let mut book = if Path::new(out_path).exists() {
-        umya::reader::xlsx::read(Path::new(out_path))?
-    } else {
-        umya::new_file()
+    last_row: u32,
+    (min_width, max_width): (f64, f64),
+    filter_buttons: bool,
+    recorded: &HashMap<u32, f64>, // column number -> width we set there last time
+) -> HashMap<String, f64> {
+    // umya gives every column it creates a width of 8.38; other widths are ours
+    // only if we recorded exactly that width for this column last time
+    let ours = |col: u32, w: f64| {
+        w <= 0.0 || (w - 8.38).abs() < 0.01 || recorded.get(&col).is_some_and(|r| (r - w).abs() < 0.01)
     };
-
-    // Ensure sheet exists (create or rename default)
-    if book.get_sheet_by_name(sheet_name).is_none() {
-        if sheet_name != "Sheet1" {
-            if let Some(ws1) = book.get_sheet_by_name_mut("Sheet1") {
-                ws1.set_name(sheet_name);
-            } else {
-                let _ = book.new_sheet(sheet_name);
+    let mut applied: HashMap<String, f64> = HashMap::new();
+    for (c_idx, key) in columns.iter().enumerate() {
+        let col = c_idx as u32 + 1;
+        if let Some(dim) = ws.get_column_dimension_by_number(&col) {
+            if !ours(col, *dim.get_width()) {
+                continue;
+            }
+        }
+        let mut chars = 0usize;
+        for r in 1..=last_row {
+            if let Some(cell) = ws.get_cell((col, r)) {
+                let text = cell.get_formatted_value();
+                let mut len = text.lines().map(|l| l.chars().count()).max().unwrap_or(0);
+                if r == 1 && filter_buttons {
+                    len += 2;
+                }
+                chars = chars.max(len);
             }
-        } else {
-            let _ = book.new_sheet(sheet_name);
         }
+        let width = (chars as f64 + 2.0).clamp(min_width, max_width);
+        ws.get_column_dimension_by_number_mut(&col).set_width(width);
+        applied.insert(key.clone(), width);
     }
+    applied
+}
 
-    // Now we can safely get it mutably
-    let ws = book
-        .get_sheet_by_name_mut(sheet_name)
-        .expect("sheet must exist");
-
-    // Header row (keeps existing styles)
-    for (c_idx, col_name) in columns.iter().enumerate() {
-        let col = (c_idx as u32) + 1;
-        ws.get_cell_mut((col, 1)).set_value(col_name);
+// Copy cell styles and row height of `source` onto the appended rows `first..=last`
+fn inherit_row_style_from(ws: &mut umya::Worksheet, source: u32, first: u32, last: u32, min_cols: u32) {
+    let (high_col, _) = ws.get_highest_column_and_row();
+    let styles: Vec<(u32, umya::Style)> = (1..=high_col.max(min_cols))
+        .filter_map(|c| ws.get_cell((c, source)).map(|cell| (c, cell.get_style().clone())))
+        .collect();
+    let height = ws
+        .get_row_dimension(&source)
+        .filter(|r| *r.get_custom_height())
+        .map(|r| *r.get_height());
+    for r in first..=last {
+        for (c, style) in &styles {
+            ws.get_cell_mut((*c, r)).set_style(style.clone());
+        }
+        if let Some(h) = height {
+            ws.get_row_dimension_mut(&r).set_height(h).set_custom_height(true);
+        }
     }
+}
 
-    // Data rows (starting at row 2) — preserves formatting of those cells
-    for (r_idx, rowmap) in rows.iter().enumerate() {
-        let row_num = (r_idx as u32) + 2;
-        for (c_idx, key) in columns.iter().enumerate() {
-            let col = (c_idx as u32) + 1;
-            let cell = ws.get_cell_mut((col, row_num));
-
-            if let Some(v) = rowmap.get(key) {
-                // If this column is mapped to a hyperlink base, write a HYPERLINK formula
-                if let Some(base) = hyperlink_map.get(key) {
-                    // Build display text from the value
-                    let text = match v {
-                        Value::Null => "".to_string(),
-                        Value::Bool(b) => b.to_string(),
-                        Value::Number(n) => n.to_string(),
-                        Value::String(s) => s.clone(),
-                        other => other.to_string(),
-                    };
-                    if !text.is_empty() {
-                        let url = format!("{}{}", base, &text);
-                        let f = format!(
-                            "HYPERLINK(\"{}\",\"{}\")",
-                            xl_quote_escape(&url),
-                            xl_quote_escape(&text)
-                        );
-                        cell.set_formula(&f);
-                        continue;
-                    } else {
-                        // empty text => write empty value (no link)
-                        cell.set_value("");
-                        continue;
-                    }
-                }
+// umya has no way to drop a cell's hyperlink, so recreate the cell with its style
+fn unlink_cell(ws: &mut umya::Worksheet, col: u32, row: u32) {
+    let Some(old) = ws.get_cell((col, row)) else {
+        return;
+    };
+    let style = old.get_style().clone();
+    let value = old.get_cell_value().clone();
+    ws.remove_cell((col, row));
+    let cell = ws.get_cell_mut((col, row));
+    cell.set_style(style);
+    cell.set_cell_value(value);
+}
 
-                // Normal write for non-hyperlink columns
-                match v {
-                    Value::Null => {
-                        cell.set_value("");
-                    }
-                    Value::Bool(b) => {
-                        cell.set_value_bool(*b);
-                    }
-                    Value::Number(n) => {
-                        if let Some(f) = n.as_f64() {
-                            cell.set_value_number(f);
-                        } else {
-                            cell.set_value(n.to_string());
-                        }
-                    }
-                    Value::String(s) => {
-                        cell.set_value(s);
-                    }
-                    other => {
-                        cell.set_value(other.to_string());
-                    }
-                }
-            }
+// Strings that are exactly a number's display form ("42", "1.5") are stored as
+// numbers; anything else ("00123", "1e3", "TRUE") stays text as given.
+fn set_text_value(cell: &mut umya::Cell, s: &str) {
+    if s.is_empty() {
+        cell.set_value("");
+        return;
+    }
+    if let Ok(f) = s.parse::<f64>() {
+        if f.is_finite() && value_to_text(&float_to_value(f)) == s {
+            cell.set_value_number(f);
+            return;
         }
     }
-
-    // Save back to same file (styles remain intact)
-    umya::writer::xlsx::write(&book, Path::new(out_path))?;
-    Ok(())
+    cell.set_value_string(s);
 }
 
 // ---------------- misc helpers ----------------
 
-fn load_config(path: &str) -> Result<ConfigFile, Box<dyn std::error::Error>> {
+// One config per `[[job]]`; a job inherits every top-level key it doesn't set,
+// and the entries of top-level tables it doesn't override.
+// Without jobs the file itself is the only job.
+fn load_jobs(path: &str) -> Result<Vec<ConfigFile>, Box<dyn std::error::Error>> {
     let txt = fs::read_to_string(path)?;
-    let cfg: ConfigFile = toml::from_str(&txt)?;
-    Ok(cfg)
+    let mut top: toml::Table = toml::from_str(&txt)?;
+    let jobs = match top.remove("job") {
+        None => return Ok(vec![toml::Value::Table(top).try_into()?]),
+        Some(toml::Value::Array(jobs)) if !jobs.is_empty() => jobs,
+        Some(_) => return Err("`job` must be a non-empty array of tables ([[job]])".into()),
+    };
+    jobs.into_iter()
+        .enumerate()
+        .map(|(i, job)| {
+            let toml::Value::Table(job) = job else {
+                return Err(format!("job #{} is not a table", i + 1).into());
+            };
+            let mut merged = top.clone();
+            for (key, value) in job {
+                // tables ([labels], [hyperlink], ...) merge by key; anything else is replaced
+                match (merged.get_mut(&key), value) {
+                    (Some(toml::Value::Table(base)), toml::Value::Table(over)) => base.extend(over),
+                    (_, value) => {
+                        merged.insert(key, value);
+                    }
+                }
+            }
+            toml::Value::Table(merged)
+                .try_into()
+                .map_err(|e| format!("job #{}: {}", i + 1, e).into())
+        })
+        .collect()
 }
 
 fn print_help(program: &str) {
     println!("Usage:");
-    println!("  {program} --out OUT.xlsx [--sheet Sheet1] [--pk col1,col2,...] \\");
-    println!("            [--array | --ndjson] \\");
+    println!("  {program} --out OUT.xlsx [--sheet Sheet1 | --sheet-by COL [--sheet 'Issues-{{value}}']] [--pk col1,col2,...] \\");
+    println!("            [--array | --ndjson] [--root /json/pointer] [--flatten [--flatten-sep SEP] [--flatten-depth N]] \\");
     println!("            [--include name1,name2,...] [--include-regex r1,r2,...] [--include-substr s1,s2,...] \\");
+    println!("            [--exclude name1,name2,...] [--exclude-regex r1,r2,...] [--exclude-substr s1,s2,...] \\");
     println!("            [--order n1,n2,...] [--order-regex r1,r2,...] [--order-substr s1,s2,...] [--order-rest existing|alpha|none] \\");
-    println!("            [--pk-first | --no-pk-first] [--link col=BASE[,col2=BASE2,...]] [--config file.toml] < input.json");
+    println!("            [--explode path1,path2,...] [--explode-key path=child,...] \\");
+    println!("            [--collapse pattern=column,...] [--collapse-sep SEP] \\");
+    println!("            [--clear none|values|remove] \\");
+    println!("            [--pk-first | --no-pk-first] [--link col=BASE[,col2=BASE2,...]] [--link-mode formula|native] \\");
+    println!("            [--type key=datetime|date|number|text,...] [--auto-dates] [--timezone utc|local|drop] \\");
+    println!("            [--label key=Label,...] [--format-existing] [--inherit-row-style] [--template-row N] \\");
+    println!("            [--table NAME [--table-style STYLE]] [--freeze header|pk|B2] [--autofilter] \\");
+    println!("            [--auto-width [--min-width N] [--max-width N]] \\");
+    println!("            [--sync none|remove|archive|mark [--archive-sheet NAME] [--status-column COL] [--strike-removed]] \\");
+    println!("            [--snapshot none|sheet|history [--snapshot-name TPL] [--history-sheet NAME] [--snapshot-keep N]] \\");
+    println!("            [--merge replace|patch|patch-non-null] [--protect col1,col2,...] \\");
+    println!("            [--audit [--audit-position first|end]] \\");
+    println!("            [--track-changes [--changed-fill RGB] [--new-fill RGB] [--changes-sheet NAME]] \\");
+    println!("            [--pk-normalize col=trim+casefold+numeric+nfc,...] [--duplicates error|first-wins|last-wins|keep-all] \\");
+    println!("            [--dry-run [--report text|json]] [--config file.toml] [--input FILE | < input.json]");
     println!();
     println!("Notes:");
     println!("  • Existing XLSX is updated in-place; formatting is preserved.");
+    println!("  • A config with [[job]] entries runs each job (own input, sheet, pk, ...) in one read/write of OUT.");
+    println!("  • --sheet-by writes one sheet per value of COL, each with its own PK merge and columns.");
     println!("  • If NDJSON is set but input starts with '[', the tool switches to array mode.");
+    println!("  • --root selects the record list inside an envelope (per line in NDJSON mode).");
+    println!("  • --flatten turns nested objects/arrays into dotted keys (fields.components.0.name).");
     println!("  • Inclusion is ACTIVE if any include list is present (exact/regex/substr).");
+    println!("  • Exclusion (exact/regex/substr) is applied after inclusion; PK columns are never excluded.");
+    println!("  • --explode emits one row per array element; the element index (<path>#) or --explode-key joins the PK.");
+    println!("  • --collapse joins indexed columns (fields.labels.\\d+) into one cell, in index order.");
+    println!("  • --clear values blanks cells outside the written range (styles kept); remove deletes those rows/columns.");
+    println!("  • --label / [labels] show a display header; a hidden `_columns` sheet maps it back to the key.");
+    println!("  • Date-typed columns (or --auto-dates) turn ISO-8601 strings into real Excel dates.");
+    println!("  • [format] (config) styles new cells per column; --format-existing restyles existing cells too.");
+    println!("  • --inherit-row-style copies the last data row's style/height (or --template-row N) onto appended rows.");
+    println!("  • --table creates (or resizes) an Excel Table over the header and data range.");
+    println!("  • --auto-width fits columns to their content but leaves hand-sized columns alone.");
+    println!("  • --pk-normalize matches PKs by a normalized form (trim, case, number, NFC); the sheet keeps the original.");
+    println!("  • Duplicate PKs (input or sheet) are reported; --duplicates decides which row wins or fails the run.");
+    println!("  • --merge patch keeps sheet values for keys the input lacks; --protect columns keep non-empty sheet values.");
+    println!("  • --audit maintains _first_seen, _last_updated and _last_seen timestamps per row.");
+    println!("  • --track-changes fills changed cells and new rows, and logs each change to a `_changes` sheet.");
+    println!("  • --dry-run prints what would change (rows and columns) and leaves the workbook untouched.");
+    println!("  • --sync removes, archives or marks existing rows whose PK is missing from the input.");
+    println!("  • --snapshot sheet copies each run into a dated sheet; history appends it to a History sheet.");
     println!("  • Column order: (PKs if pk_first) -> ordered groups -> remainder (order-rest).");
     println!("  • --link/ [hyperlink] will write a HYPERLINK formula so the cell shows only the value but is clickable.");
+    println!("  • --link-mode native writes the plain value with a real cell hyperlink instead of a formula.");
 }
 
 fn has_flag(args: &[String], flag: &str) -> bool {
@@ -672,9 +4088,7 @@ fn split_csv(s: &str) -> Vec<String> {
 }
 
 fn split_once_eq(s: &str) -> Option<(&str, &str)> {
-    let mut it = s.splitn(2, '=');
-    let k = it.next()?;
-    let v = it.next()?;
+    let (k, v) = s.split_once('=')?;
     Some((k.trim(), v.trim()))
 }
 
@@ -685,8 +4099,9 @@ fn push_unique(vec: &mut Vec<String>, seen: &mut HashSet<String>, k: String) {
 }
 
 // Natural sort so ...comments.2... < ...comments.10...
+#[allow(clippy::ptr_arg)] // used directly as a `sort_by` comparator on Vec<String>
 fn natural_cmp(a: &String, b: &String) -> Ordering {
-    natural_cmp_str(a.as_str(), b.as_str())
+    natural_cmp_str(a, b)
 }
 fn natural_cmp_str(a: &str, b: &str) -> Ordering {
     let pa = natural_parts(a);
@@ -747,7 +4162,210 @@ fn natural_parts(s: &str) -> Vec<NatPart> {
     out
 }
 
+// Null or empty string: nothing in the cell
+fn is_blank(v: &Value) -> bool {
+    match v {
+        Value::Null => true,
+        Value::String(s) => s.is_empty(),
+        _ => false,
+    }
+}
+
+// Plain cell text for a JSON value (strings unquoted, null empty)
+fn value_to_text(v: &Value) -> String {
+    match v {
+        Value::Null => "".to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+// Allow `\n` / `\t` in delimiters given on the CLI or in TOML literal strings
+fn unescape_sep(s: &str) -> String {
+    s.replace("\\n", "\n").replace("\\t", "\t")
+}
+
 // Excel formula quote-escape: " -> ""
 fn xl_quote_escape(s: &str) -> String {
     s.replace('"', "\"\"")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S%.f").unwrap()
+    }
+
+    #[test]
+    fn iso_datetime_offsets() {
+        let jira = "2024-05-01T10:22:33.000+0200";
+        assert_eq!(parse_iso_datetime(jira, "utc"), Some((at("2024-05-01 08:22:33"), true)));
+        assert_eq!(parse_iso_datetime(jira, "drop"), Some((at("2024-05-01 10:22:33"), true)));
+        assert_eq!(
+            parse_iso_datetime("2024-05-01 00:30:00-0130", "utc"),
+            Some((at("2024-05-01 02:00:00"), true))
+        );
+        assert_eq!(
+            parse_iso_datetime("2024-05-01T10:22:33.5+02:00", "utc"),
+            Some((at("2024-05-01 08:22:33.5"), true))
+        );
+        assert_eq!(
+            parse_iso_datetime("2024-05-01T01:00:00Z", "drop"),
+            Some((at("2024-05-01 01:00:00"), true))
+        );
+    }
+
+    #[test]
+    fn iso_datetime_without_offset() {
+        assert_eq!(parse_iso_datetime("2024-05-01", "utc"), Some((at("2024-05-01 00:00:00"), false)));
+        assert_eq!(parse_iso_datetime(" 2024-05-01T10:22 ", "utc"), Some((at("2024-05-01 10:22:00"), true)));
+        assert_eq!(parse_iso_datetime("2024-05-01T10:22:33", "local"), Some((at("2024-05-01 10:22:33"), true)));
+        assert_eq!(parse_iso_datetime("2024-13-01", "utc"), None);
+        assert_eq!(parse_iso_datetime("ABC-2024-05", "utc"), None);
+        assert_eq!(parse_iso_datetime("2024-05-01Tnoon", "utc"), None);
+    }
+
+    #[test]
+    fn element_lines_top_level() {
+        let input = "[\n  {\"id\": 1},\n  {\"id\": 2,\n   \"tags\": [1,\n     2]},\n\n  {\"id\": 3}\n]";
+        assert_eq!(array_element_lines(input, ""), vec![2, 3, 7]);
+        assert_eq!(array_element_lines("[{\"id\": 1}, {\"id\": 2}]", ""), vec![1, 1]);
+        assert_eq!(array_element_lines("[]", ""), Vec::<usize>::new());
+        assert_eq!(array_element_lines("{\"id\": 1}", ""), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn element_lines_skip_escaped_strings() {
+        // quotes, backslashes, brackets and newlines inside strings are not structure
+        let input = "[\n{\"a\\\"[\": \"x\\\\\", \"b\": \"]\\n[\"},\n{\"c\": \"\\\"\"}\n]";
+        assert!(serde_json::from_str::<Value>(input).is_ok());
+        assert_eq!(array_element_lines(input, ""), vec![2, 3]);
+    }
+
+    #[test]
+    fn element_lines_nested_pointer() {
+        let input = "{\"total\": 2,\n \"data\": {\"items\": [\n   {\"id\": 1, \"items\": [9]},\n   {\"id\": 2}\n ]},\n \"items\": [0]}";
+        assert_eq!(array_element_lines(input, "/data/items"), vec![3, 4]);
+        assert_eq!(array_element_lines(input, "/items"), vec![6]);
+        assert_eq!(array_element_lines(input, "/data/items/0/items"), vec![3]);
+        assert_eq!(array_element_lines(input, "/data/missing"), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn element_lines_escaped_pointer_segments() {
+        let input = "{\"a/b\": [\n 1],\n \"c~d\": {\"\": [1,\n 2]}}";
+        assert_eq!(array_element_lines(input, "/a~1b"), vec![2]);
+        assert_eq!(array_element_lines(input, "/c~0d/"), vec![3, 4]);
+        assert_eq!(array_element_lines(input, "/a/b"), Vec::<usize>::new());
+        assert_eq!(root_to_pointer("a/b"), "/a~1b");
+        assert_eq!(root_to_pointer("data.items"), "/data/items");
+    }
+
+    fn norm(spec: &str, s: &str) -> String {
+        PkNorm::parse(spec).unwrap().apply(s.to_string())
+    }
+
+    #[test]
+    fn pk_norm_each_option() {
+        assert_eq!(norm("none", " ABC-1 "), " ABC-1 ");
+        assert_eq!(norm("trim", " ABC-1\t"), "ABC-1");
+        assert_eq!(norm("casefold", "ABC-1"), "abc-1");
+        assert_eq!(norm("nfc", "e\u{301}"), "\u{e9}");
+        assert_eq!(norm("numeric", "042"), "42");
+        assert_eq!(norm("numeric", "42.0"), "42");
+        assert_eq!(norm("numeric", " 4.50 "), "4.5");
+        assert_eq!(norm("numeric", "1e3"), "1000");
+        assert_eq!(norm("numeric", "ABC-1"), "ABC-1");
+        assert_eq!(norm("numeric", "NaN"), "NaN");
+    }
+
+    #[test]
+    fn pk_norm_combined() {
+        assert_eq!(norm("trim+casefold+nfc", "  Cafe\u{301} "), norm("all", "caf\u{e9}"));
+        assert_eq!(norm("trim|numeric", " 007 "), "7");
+        assert_eq!(norm(" Case + Lower ", "X"), "x");
+        assert!(PkNorm::parse("trim+upper").is_err());
+
+        let row: RowMap = [("k".to_string(), Value::from(" abc ")), ("n".to_string(), Value::from(42))]
+            .into_iter()
+            .collect();
+        let pk = |norms: &HashMap<String, PkNorm>| {
+            composite_pk(&row, &["k".to_string(), "n".to_string()], &[], norms).unwrap()
+        };
+        let all: HashMap<String, PkNorm> = [("*".to_string(), PkNorm::parse("all").unwrap())].into();
+        assert_eq!(pk(&all), "abc\u{1F}42");
+        let only_n: HashMap<String, PkNorm> = [("n".to_string(), PkNorm::parse("numeric").unwrap())].into();
+        assert_eq!(pk(&only_n), " abc \u{1F}42");
+    }
+
+    #[test]
+    fn snapshot_names_fit_and_keep_apart() {
+        assert_eq!(snapshot_sheet_name("{sheet} {date}", "Jira", "2026-10-16"), "Jira 2026-10-16");
+        assert_eq!(snapshot_sheet_name("Snap {date}", "Jira", "2026-10-16"), "Snap 2026-10-16");
+
+        let a = snapshot_sheet_name("{sheet} {date}", "Project overview - team alpha", "2026-10-16");
+        let b = snapshot_sheet_name("{sheet} {date}", "Project overview - team beta", "2026-10-16");
+        assert_eq!(a.chars().count(), 31);
+        assert!(a.starts_with("Project overvie~") && a.ends_with(" 2026-10-16"));
+        assert_ne!(a, b);
+        assert_eq!(a, snapshot_sheet_name("{sheet} {date}", "Project overview - team alpha", "2026-10-16"));
+
+        let exact = "x".repeat(20);
+        assert_eq!(snapshot_sheet_name("{sheet} {date}", &exact, "2026-10-16"), format!("{} 2026-10-16", exact));
+        let over = snapshot_sheet_name("{sheet} {date}", &"x".repeat(21), "2026-10-16");
+        assert_eq!(over.chars().count(), 31);
+        assert!(over.contains('~'));
+    }
+
+    #[test]
+    fn sheet_names_sanitized() {
+        assert_eq!(sanitize_sheet_name("a/b:c[d]*e?f\\g"), "a_b_c_d__e_f_g");
+        assert_eq!(sanitize_sheet_name("'quoted'"), "quoted");
+        assert_eq!(sanitize_sheet_name("''"), "_");
+        assert_eq!(sanitize_sheet_name("   "), "_");
+        let long = format!("{}'tail", "y".repeat(30));
+        assert_eq!(sanitize_sheet_name(&long), "y".repeat(30));
+        assert_eq!(sanitize_sheet_name(&"é".repeat(40)).chars().count(), 31);
+    }
+
+    #[test]
+    fn numbered_names_fit_31() {
+        let base = "z".repeat(31);
+        assert_eq!(numbered(&base, 1), base);
+        assert_eq!(numbered(&base, 2), format!("{} (2)", "z".repeat(27)));
+        assert_eq!(numbered(&base, 12), format!("{} (12)", "z".repeat(26)));
+        assert_eq!(numbered("Issues", 3), "Issues (3)");
+
+        assert!(fits_base("Issues", "Issues"));
+        assert!(fits_base("Issues (3)", "Issues"));
+        assert!(!fits_base("Issues (1)", "Issues"));
+        assert!(!fits_base("Issues (x)", "Issues"));
+        assert!(!fits_base("Other (2)", "Issues"));
+        assert!(fits_base(&numbered(&base, 12), &base));
+    }
+
+    #[test]
+    fn split_sheets_avoid_collisions() {
+        let row = |v: &str| ("in".to_string(), [("team".to_string(), Value::from(v))].into_iter().collect::<RowMap>());
+        let rows = vec![row("sheet1"), row("Ops"), row("OPS"), row("Reserved"), row("")];
+        let book = umya::new_file(); // holds "Sheet1"
+        let reserved: HashSet<String> = ["reserved".to_string()].into();
+        let names: Vec<String> = split_by_sheet(&book, rows, "team", "{value}", &reserved)
+            .into_iter()
+            .map(|s| s.name)
+            .collect();
+        assert_eq!(names, vec!["(blank)", "OPS", "Ops (2)", "Reserved (2)", "sheet1 (2)"]);
+
+        let long = "w".repeat(40);
+        let rows = vec![row(&format!("{}a", long)), row(&format!("{}b", long))];
+        let names: Vec<String> = split_by_sheet(&book, rows, "team", "{value}", &HashSet::new())
+            .into_iter()
+            .map(|s| s.name)
+            .collect();
+        assert_eq!(names, vec!["w".repeat(31), format!("{} (2)", "w".repeat(27))]);
+    }
+}
@@ -0,0 +1,65 @@
+use calamine::{open_workbook_auto, Reader};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+const INPUT: &str = r#"[{"key":"ABC-1","n":1},{"key":"ABC-2","n":2}]"#;
+
+fn run(config: &Path, mode: &str, input: &str) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_flatjson_to_xlsx"))
+        .args(["-c", config.to_str().unwrap(), "--link-mode", mode])
+        .stdin(Stdio::piped())
+        .spawn()
+        .expect("run binary");
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    assert!(child.wait().unwrap().success());
+}
+
+// Cell values and formulas of Sheet1
+fn sheet(path: &Path) -> (Vec<Vec<String>>, Vec<Vec<String>>) {
+    let mut wb = open_workbook_auto(path).unwrap();
+    let values = wb.worksheet_range("Sheet1").unwrap().unwrap();
+    let formulas = wb.worksheet_formula("Sheet1").unwrap().unwrap();
+    (
+        values.rows().map(|r| r.iter().map(|c| c.to_string()).collect()).collect(),
+        formulas.rows().map(|r| r.to_vec()).collect(),
+    )
+}
+
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("flatjson_to_xlsx_{}_{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn link_text_template_survives_reruns() {
+    for mode in ["formula", "native"] {
+        let dir = scratch(mode);
+        let out = dir.join("out.xlsx");
+        let config = dir.join("config.toml");
+        fs::write(
+            &config,
+            format!(
+                "out = {:?}\npk = [\"key\"]\n[hyperlink]\nkey = {{ url = \"https://jira/browse/{{value}}\", text = \"Open {{value}}\" }}\n",
+                out.to_str().unwrap()
+            ),
+        )
+        .unwrap();
+
+        run(&config, mode, INPUT);
+        let first = sheet(&out);
+        assert_eq!(first.0.len(), 3, "{mode}: header + 2 rows");
+        assert_eq!(first.0[1][0], "Open ABC-1");
+
+        // same input again, then only one of the rows: nothing may change
+        run(&config, mode, INPUT);
+        assert_eq!(sheet(&out), first, "{mode}: second run changed the sheet");
+        run(&config, mode, r#"[{"key":"ABC-2","n":2}]"#);
+        assert_eq!(sheet(&out), first, "{mode}: untouched row changed");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}